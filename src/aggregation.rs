@@ -0,0 +1,297 @@
+use std::io::Read;
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::auth::Auth;
+use crate::db::Error;
+
+///////////////////////////////////////////////////////////////////////////////
+// AGGREGATIONS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregation {
+    /// Number of matching entities.
+    Count,
+    /// Number of matching entities, counting at most the given number.
+    CountUpTo(i64),
+    /// Sum of a numeric property over the matching entities.
+    Sum(String),
+    /// Average of a numeric property over the matching entities.
+    Avg(String),
+}
+
+impl Aggregation {
+    fn to_json(&self, alias: String) -> serde_json::Value {
+        let property = |name: &String| serde_json::json!({"property": {"name": name}});
+        match self {
+            Aggregation::Count => serde_json::json!({"alias": alias, "count": {}}),
+            Aggregation::CountUpTo(up_to) => serde_json::json!({
+                "alias": alias,
+                "count": {"upTo": up_to.to_string()},
+            }),
+            Aggregation::Sum(name) => serde_json::json!({"alias": alias, "sum": property(name)}),
+            Aggregation::Avg(name) => serde_json::json!({"alias": alias, "avg": property(name)}),
+        }
+    }
+}
+
+/// Result of a single aggregation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateValue {
+    Integer(i64),
+    Double(f64),
+    /// E.g. the average over no entities.
+    Null,
+}
+
+impl AggregateValue {
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            AggregateValue::Integer(x) => Some(*x),
+            _ => None,
+        }
+    }
+    /// Integers are converted, possibly losing precision.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            AggregateValue::Integer(x) => Some(*x as f64),
+            AggregateValue::Double(x) => Some(*x),
+            AggregateValue::Null => None,
+        }
+    }
+    fn from_datastore(value: google_datastore1::Value) -> Option<Self> {
+        if let Some(x) = value.integer_value {
+            x.parse().ok().map(AggregateValue::Integer)
+        } else if let Some(x) = value.double_value {
+            Some(AggregateValue::Double(x))
+        } else if value.null_value.is_some() {
+            Some(AggregateValue::Null)
+        } else {
+            None
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// REST ENDPOINT
+///////////////////////////////////////////////////////////////////////////////
+
+// `runAggregationQuery` is newer than the `google-datastore1` bindings, so it
+// is called directly, reusing the generated types where they fit.
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunAggregationQueryResponse {
+    batch: Option<AggregationResultBatch>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AggregationResultBatch {
+    aggregation_results: Option<Vec<AggregationResult>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AggregationResult {
+    aggregate_properties: Option<HashMap<String, google_datastore1::Value>>,
+}
+
+/// Unset fields of the generated types serialize as `null`; drop them as
+/// the generated request builders do.
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(xs) => {
+            xs.retain(|_, x| !x.is_null());
+            xs.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(xs) => {
+            xs.iter_mut().for_each(remove_nulls);
+        }
+        _ => {}
+    }
+}
+
+/// Aggregations are named by their position in the request.
+fn alias(ix: usize) -> String {
+    format!("aggregation_{}", ix)
+}
+
+fn request_body(
+    partition_id: Option<google_datastore1::PartitionId>,
+    read_options: Option<google_datastore1::ReadOptions>,
+    nested_query: google_datastore1::Query,
+    aggregations: &[Aggregation],
+) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "partitionId": partition_id,
+        "readOptions": read_options,
+        "aggregationQuery": {
+            "nestedQuery": nested_query,
+            "aggregations": aggregations
+                .iter()
+                .enumerate()
+                .map(|(ix, x)| x.to_json(alias(ix)))
+                .collect::<Vec<_>>(),
+        },
+    });
+    remove_nulls(&mut body);
+    body
+}
+
+/// Returns the values of the first `count` aggregations, in order.
+fn parse_response(text: &str, count: usize) -> Result<Vec<AggregateValue>, Error> {
+    let mut properties = serde_json::from_str::<RunAggregationQueryResponse>(text)
+        .map_err(|e| Error::Deserialization {msg: format!("{}", e)})?
+        .batch
+        .and_then(|x| x.aggregation_results)
+        .and_then(|x| x.into_iter().next())
+        .and_then(|x| x.aggregate_properties)
+        .ok_or(Error::NoPayload)?;
+    (0..count)
+        .map(|ix| {
+            properties
+                .remove(&alias(ix))
+                .and_then(AggregateValue::from_datastore)
+                .ok_or(Error::NoPayload)
+        })
+        .collect()
+}
+
+/// Returns one value per aggregation, in order.
+pub(crate) fn run_aggregation_query(
+    http: &hyper::Client,
+    auth: &Auth,
+    project_id: &str,
+    partition_id: Option<google_datastore1::PartitionId>,
+    read_options: Option<google_datastore1::ReadOptions>,
+    nested_query: google_datastore1::Query,
+    aggregations: &[Aggregation],
+) -> Result<Vec<AggregateValue>, Error> {
+    let body = request_body(partition_id, read_options, nested_query, aggregations);
+    let token = yup_oauth2::GetToken::token(
+        &mut auth.clone(),
+        &["https://www.googleapis.com/auth/cloud-platform"],
+    );
+    let token = token.map_err(|e| Error::Auth {msg: e.to_string()})?;
+    let url = format!(
+        "https://datastore.googleapis.com/v1/projects/{}:runAggregationQuery",
+        project_id,
+    );
+    let body = body.to_string();
+    let mut response = http
+        .post(&url)
+        .header(hyper::header::Authorization(hyper::header::Bearer {
+            token: token.access_token,
+        }))
+        .header(hyper::header::ContentType::json())
+        .body(body.as_str())
+        .send()
+        .map_err(|e| Error::Transport {msg: e.to_string()})?;
+    let mut text = String::new();
+    response
+        .read_to_string(&mut text)
+        .map_err(|e| Error::Transport {msg: e.to_string()})?;
+    if !response.status.is_success() {
+        return Err(Error::from_response_body(response.status.to_u16(), &text));
+    }
+    parse_response(&text, aggregations.len())
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn kind_query(kind: &str) -> google_datastore1::Query {
+        google_datastore1::Query {
+            kind: Some(vec![google_datastore1::KindExpression {name: Some(String::from(kind))}]),
+            ..google_datastore1::Query::default()
+        }
+    }
+
+    #[test]
+    fn to_json() {
+        assert_eq!(
+            Aggregation::Count.to_json(String::from("a")),
+            json!({"alias": "a", "count": {}}),
+        );
+        assert_eq!(
+            Aggregation::CountUpTo(1000).to_json(String::from("a")),
+            json!({"alias": "a", "count": {"upTo": "1000"}}),
+        );
+        assert_eq!(
+            Aggregation::Sum(String::from("price")).to_json(String::from("a")),
+            json!({"alias": "a", "sum": {"property": {"name": "price"}}}),
+        );
+        assert_eq!(
+            Aggregation::Avg(String::from("price")).to_json(String::from("a")),
+            json!({"alias": "a", "avg": {"property": {"name": "price"}}}),
+        );
+    }
+
+    #[test]
+    fn request_body_aliases_aggregations_and_drops_nulls() {
+        let aggregations = [Aggregation::Count, Aggregation::Sum(String::from("price"))];
+        let body = request_body(None, None, kind_query("Item"), &aggregations);
+        assert_eq!(body, json!({
+            "aggregationQuery": {
+                "nestedQuery": {"kind": [{"name": "Item"}]},
+                "aggregations": [
+                    {"alias": "aggregation_0", "count": {}},
+                    {"alias": "aggregation_1", "sum": {"property": {"name": "price"}}},
+                ],
+            },
+        }));
+    }
+
+    #[test]
+    fn remove_nulls_recurses() {
+        let mut value = json!({"a": null, "b": [{"c": null, "d": 1}], "e": {"f": null}});
+        remove_nulls(&mut value);
+        assert_eq!(value, json!({"b": [{"d": 1}], "e": {}}));
+    }
+
+    #[test]
+    fn response_values_in_order() {
+        let text = json!({
+            "batch": {
+                "aggregationResults": [{
+                    "aggregateProperties": {
+                        "aggregation_2": {"nullValue": "NULL_VALUE"},
+                        "aggregation_0": {"integerValue": "42"},
+                        "aggregation_1": {"doubleValue": 2.5},
+                    },
+                }],
+                "moreResults": "NO_MORE_RESULTS",
+                "readTime": "2020-01-02T03:04:05.123456Z",
+            },
+        }).to_string();
+        let values = parse_response(&text, 3).unwrap();
+        assert_eq!(values, vec![
+            AggregateValue::Integer(42),
+            AggregateValue::Double(2.5),
+            AggregateValue::Null,
+        ]);
+        assert_eq!(values[0].as_f64(), Some(42.0));
+        assert_eq!(values[1].as_i64(), None);
+    }
+
+    #[test]
+    fn missing_response_values_fail() {
+        let text = json!({
+            "batch": {"aggregationResults": [{"aggregateProperties": {
+                "aggregation_0": {"integerValue": "42"},
+            }}]},
+        }).to_string();
+        assert!(matches!(parse_response(&text, 2), Err(Error::NoPayload)));
+        assert!(matches!(parse_response("{}", 1), Err(Error::NoPayload)));
+        assert!(matches!(parse_response("not json", 1), Err(Error::Deserialization {..})));
+    }
+}
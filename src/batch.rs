@@ -0,0 +1,146 @@
+use std::ops::Range;
+use serde::Serialize;
+use crate::db::{CommitResult, DatastoreClient, EntityKey, Error, KeyId, KeyPath, PathElement};
+
+///////////////////////////////////////////////////////////////////////////////
+// MUTATIONS
+///////////////////////////////////////////////////////////////////////////////
+
+fn mutation() -> google_datastore1::Mutation {
+    google_datastore1::Mutation {
+        insert: None,
+        delete: None,
+        update: None,
+        base_version: None,
+        upsert: None,
+    }
+}
+
+pub(crate) fn insert_mutation(entity: google_datastore1::Entity) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {insert: Some(entity), ..mutation()}
+}
+
+pub(crate) fn upsert_mutation(entity: google_datastore1::Entity) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {upsert: Some(entity), ..mutation()}
+}
+
+pub(crate) fn update_mutation(entity: google_datastore1::Entity) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {update: Some(entity), ..mutation()}
+}
+
+pub(crate) fn delete_mutation(key: google_datastore1::Key) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {delete: Some(key), ..mutation()}
+}
+
+/// Makes `mutation` apply only if the stored entity has the given version.
+pub(crate) fn with_base_version(mutation: google_datastore1::Mutation, version: i64) -> google_datastore1::Mutation {
+    google_datastore1::Mutation {base_version: Some(version.to_string()), ..mutation}
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// WRITE BATCH
+///////////////////////////////////////////////////////////////////////////////
+
+/// Maximum number of mutations Datastore accepts in a single commit.
+pub const MAX_MUTATIONS_PER_COMMIT: usize = 500;
+
+/// Outcome of committing one chunk of a `WriteBatch`.
+#[derive(Debug)]
+pub struct ChunkResult {
+    /// Indices of the chunk's writes, in the order they were added.
+    pub range: Range<usize>,
+    pub result: Result<CommitResult, Error>,
+}
+
+/// A list of writes of any kind and mutation type, committed in chunks of
+/// at most `MAX_MUTATIONS_PER_COMMIT`.
+///
+/// Each chunk is committed independently: a failed chunk does not undo the
+/// ones before it, and later chunks are still attempted.
+///
+/// ```no_run
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Clone, Serialize, Deserialize)]
+/// # pub struct TodoItem { pub name: String, pub title: String }
+/// # impl ddb::EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String { String::from("TodoItem") }
+/// #     fn entity_name_key(&self) -> String { self.name.clone() }
+/// # }
+/// # let items: Vec<TodoItem> = Vec::new();
+/// let db = ddb::DatastoreClient::new().unwrap();
+/// let mut batch = db.write_batch();
+/// for item in items {
+///     batch.upsert(item).unwrap();
+/// }
+/// batch.delete::<TodoItem, _>("stale");
+/// for chunk in batch.commit() {
+///     if let Err(e) = chunk.result {
+///         eprintln!("writes {:?} failed: {:?}", chunk.range, e);
+///     }
+/// }
+/// ```
+pub struct WriteBatch {
+    client: DatastoreClient,
+    mutations: Vec<google_datastore1::Mutation>,
+}
+
+impl WriteBatch {
+    pub(crate) fn new(client: DatastoreClient) -> Self {
+        WriteBatch {
+            client,
+            mutations: Vec::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.mutations.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+    pub fn insert<T: Serialize + EntityKey>(&mut self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.mutations.push(insert_mutation(entity));
+        Ok(())
+    }
+    pub fn upsert<T: Serialize + EntityKey>(&mut self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.mutations.push(upsert_mutation(entity));
+        Ok(())
+    }
+    pub fn update<T: Serialize + EntityKey>(&mut self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.mutations.push(update_mutation(entity));
+        Ok(())
+    }
+    pub fn delete<T: EntityKey, K: Into<KeyId>>(&mut self, key: K) {
+        self.delete_with_ancestors::<T, K>(&[], key)
+    }
+    pub fn delete_with_ancestors<T: EntityKey, K: Into<KeyId>>(&mut self, ancestors: &[PathElement], key: K) {
+        self.delete_by_path(KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key))
+    }
+    pub fn delete_by_path(&mut self, key: KeyPath) {
+        let key = self.client.to_key(key.into_datastore_path());
+        self.mutations.push(delete_mutation(key));
+    }
+    /// Commits the writes in order, one chunk at a time.
+    pub fn commit(self) -> Vec<ChunkResult> {
+        let client = self.client;
+        let mut start = 0;
+        let mut results = Vec::new();
+        let mut mutations = self.mutations.into_iter().peekable();
+        while mutations.peek().is_some() {
+            let chunk = mutations
+                .by_ref()
+                .take(MAX_MUTATIONS_PER_COMMIT)
+                .collect::<Vec<_>>();
+            let range = start .. start + chunk.len();
+            start = range.end;
+            results.push(ChunkResult {
+                range,
+                result: client.commit_mutations(chunk),
+            });
+        }
+        results
+    }
+}
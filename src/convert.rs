@@ -1,177 +1,921 @@
 use std::str::FromStr;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
+use crate::error::{Error, InvalidEntity};
+use crate::key::{self, KeyPath};
 
-pub fn to_datastore_value<T: Serialize>(x: T) -> Option<google_datastore1::Value> {
-    match serde_json::to_value(x).ok()? {
-        serde_json::Value::Null => {
-            Some(google_datastore1::Value {
-                null_value: Some(String::from("NULL_VALUE")),
-                ..google_datastore1::Value::default()
-            })
+///////////////////////////////////////////////////////////////////////////////
+// MARKERS
+///////////////////////////////////////////////////////////////////////////////
+
+// Datastore has value types that serde's data model lacks. Types that map to
+// them serialize as a newtype struct with one of these names, which the
+// serializer below recognizes; other serializers just see the inner value.
+
+/// Wraps an RFC 3339 string to be stored as `timestamp_value`.
+pub(crate) const TIMESTAMP: &str = "$ddb::Timestamp";
+/// Wraps a struct with `latitude` and `longitude` to be stored as
+/// `geo_point_value`.
+pub(crate) const GEO_POINT: &str = "$ddb::GeoPoint";
+/// Wraps a list of `{kind, name}` or `{kind, id}` path elements to be stored
+/// as `key_value`.
+pub(crate) const KEY: &str = "$ddb::Key";
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TO DATASTORE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Converts `x` into a Datastore value, e.g. an `entity_value` for structs.
+pub(crate) fn to_datastore_value<T: Serialize>(x: T) -> Result<google_datastore1::Value, Error> {
+    x.serialize(ValueSerializer)
+}
+
+fn null_value() -> google_datastore1::Value {
+    google_datastore1::Value {
+        null_value: Some(String::from("NULL_VALUE")),
+        ..google_datastore1::Value::default()
+    }
+}
+
+fn integer_value<T: ToString>(x: T) -> google_datastore1::Value {
+    google_datastore1::Value {
+        integer_value: Some(x.to_string()),
+        ..google_datastore1::Value::default()
+    }
+}
+
+fn string_value(x: String) -> google_datastore1::Value {
+    google_datastore1::Value {
+        string_value: Some(x),
+        ..google_datastore1::Value::default()
+    }
+}
+
+fn array_value(xs: Vec<google_datastore1::Value>) -> google_datastore1::Value {
+    google_datastore1::Value {
+        array_value: Some(google_datastore1::ArrayValue {
+            values: Some(xs)
+        }),
+        ..google_datastore1::Value::default()
+    }
+}
+
+fn entity_value(xs: HashMap<String, google_datastore1::Value>) -> google_datastore1::Value {
+    google_datastore1::Value {
+        entity_value: Some(google_datastore1::Entity {
+            properties: Some(xs),
+            key: None,
+        }),
+        ..google_datastore1::Value::default()
+    }
+}
+
+/// Wraps `value` in a single-property entity, as serde does for enum variants.
+fn variant_value(variant: &str, value: google_datastore1::Value) -> google_datastore1::Value {
+    let mut xs = HashMap::new();
+    xs.insert(variant.to_owned(), value);
+    entity_value(xs)
+}
+
+fn to_datastore_path_element(value: &google_datastore1::Value) -> Option<google_datastore1::PathElement> {
+    let properties = value.entity_value.as_ref()?.properties.as_ref()?;
+    let get = |name: &str| properties.get(name);
+    Some(google_datastore1::PathElement {
+        kind: Some(get("kind")?.string_value.clone()?),
+        name: get("name").and_then(|x| x.string_value.clone()),
+        id: get("id").and_then(|x| x.integer_value.clone()),
+    })
+}
+
+/// Places key values nested anywhere in `value` in `partition_id`, as keys
+/// without one refer to the default namespace.
+pub(crate) fn set_key_partitions(value: &mut google_datastore1::Value, partition_id: &google_datastore1::PartitionId) {
+    if let Some(key) = &mut value.key_value {
+        if key.partition_id.is_none() {
+            key.partition_id = Some(partition_id.clone());
         }
-        serde_json::Value::Bool(x) => {
-            Some(google_datastore1::Value {
-                boolean_value: Some(x),
-                ..google_datastore1::Value::default()
-            })
+    }
+    if let Some(xs) = value.entity_value.as_mut().and_then(|x| x.properties.as_mut()) {
+        xs.values_mut().for_each(|x| set_key_partitions(x, partition_id));
+    }
+    if let Some(xs) = value.array_value.as_mut().and_then(|x| x.values.as_mut()) {
+        xs.iter_mut().for_each(|x| set_key_partitions(x, partition_id));
+    }
+}
+
+const MAX_INDEXED_BLOB_LEN: usize = 1500;
+
+/// Serializes directly into a Datastore value, following the same shapes as
+/// `serde_json`, so that entities written before markers existed still read
+/// back the same way.
+struct ValueSerializer;
+
+impl serde::Serializer for ValueSerializer {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeEntity;
+    type SerializeStruct = SerializeEntity;
+    type SerializeStructVariant = SerializeEntity;
+
+    fn serialize_bool(self, x: bool) -> Result<Self::Ok, Error> {
+        Ok(google_datastore1::Value {
+            boolean_value: Some(x),
+            ..google_datastore1::Value::default()
+        })
+    }
+    fn serialize_i8(self, x: i8) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_i16(self, x: i16) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_i32(self, x: i32) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_i64(self, x: i64) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_u8(self, x: u8) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_u16(self, x: u16) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_u32(self, x: u32) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_u64(self, x: u64) -> Result<Self::Ok, Error> {
+        Ok(integer_value(x))
+    }
+    fn serialize_f32(self, x: f32) -> Result<Self::Ok, Error> {
+        self.serialize_f64(f64::from(x))
+    }
+    fn serialize_f64(self, x: f64) -> Result<Self::Ok, Error> {
+        // The REST API encodes values as JSON, which has no NaN or infinity.
+        if !x.is_finite() {
+            return Ok(null_value());
         }
-        serde_json::Value::Number(x) => {
-            if x.is_f64() {
-                Some(google_datastore1::Value {
-                    double_value: Some(x.as_f64().expect("should be f64")),
-                    ..google_datastore1::Value::default()
-                })
-            } else if x.is_i64() {
-                Some(google_datastore1::Value {
-                    integer_value: Some(format!("{}", x.as_i64().expect("should be i64"))),
-                    ..google_datastore1::Value::default()
-                })
-            } else if x.is_u64() {
-                Some(google_datastore1::Value {
-                    integer_value: Some(format!("{}", x.as_u64().expect("should be u64"))),
+        Ok(google_datastore1::Value {
+            double_value: Some(x),
+            ..google_datastore1::Value::default()
+        })
+    }
+    fn serialize_char(self, x: char) -> Result<Self::Ok, Error> {
+        Ok(string_value(x.to_string()))
+    }
+    fn serialize_str(self, x: &str) -> Result<Self::Ok, Error> {
+        Ok(string_value(x.to_owned()))
+    }
+    fn serialize_bytes(self, xs: &[u8]) -> Result<Self::Ok, Error> {
+        Ok(google_datastore1::Value {
+            blob_value: Some(base64::encode(xs)),
+            // Datastore rejects indexed blobs over 1500 bytes.
+            exclude_from_indexes: if xs.len() > MAX_INDEXED_BLOB_LEN {Some(true)} else {None},
+            ..google_datastore1::Value::default()
+        })
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(null_value())
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(null_value())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(null_value())
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        Ok(string_value(variant.to_owned()))
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        let value = value.serialize(self)?;
+        match name {
+            TIMESTAMP => {
+                let timestamp = value.string_value.ok_or_else(|| Error::Serialization {
+                    msg: String::from("timestamp must serialize as a string"),
+                })?;
+                Ok(google_datastore1::Value {
+                    timestamp_value: Some(timestamp),
                     ..google_datastore1::Value::default()
                 })
-            } else {
-                panic!("ddb from_datastore_value unreachable");
             }
-        }
-        serde_json::Value::String(x) => {
-            Some(google_datastore1::Value {
-                string_value: Some(x),
-                ..google_datastore1::Value::default()
-            })
-        }
-        serde_json::Value::Array(xs) => {
-            let mut any_invalid = false;
-            let xs = xs
-                .into_iter()
-                .filter_map(|x| {
-                    let x = to_datastore_value(x);
-                    if x.is_none() {
-                        any_invalid = true;
-                    }
-                    x
-                })
-                .collect::<Vec<_>>();
-            if !any_invalid {
-                Some(google_datastore1::Value {
-                    array_value: Some(google_datastore1::ArrayValue {
-                        values: Some(xs)
+            GEO_POINT => {
+                let coordinate = |name: &str| {
+                    value.entity_value
+                        .as_ref()
+                        .and_then(|x| x.properties.as_ref())
+                        .and_then(|xs| xs.get(name))
+                        .and_then(|x| x.double_value)
+                };
+                match (coordinate("latitude"), coordinate("longitude")) {
+                    (Some(latitude), Some(longitude)) => Ok(google_datastore1::Value {
+                        geo_point_value: Some(google_datastore1::LatLng {
+                            latitude: Some(latitude),
+                            longitude: Some(longitude),
+                        }),
+                        ..google_datastore1::Value::default()
+                    }),
+                    _ => Err(Error::Serialization {
+                        msg: String::from("geo point must have a finite latitude and longitude"),
+                    }),
+                }
+            }
+            KEY => {
+                let path = value.array_value
+                    .and_then(|x| x.values)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(to_datastore_path_element)
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|xs| !xs.is_empty())
+                    .ok_or_else(|| Error::Serialization {
+                        msg: String::from("key must serialize as a path of `{kind, name}` or `{kind, id}` elements"),
+                    })?;
+                Ok(google_datastore1::Value {
+                    key_value: Some(google_datastore1::Key {
+                        path: Some(path),
+                        partition_id: None,
                     }),
                     ..google_datastore1::Value::default()
                 })
-            } else {
-                None
             }
+            _ => Ok(value),
         }
-        serde_json::Value::Object(xs) => {
-            let mut any_invalid = false;
-            let xs = xs
-                .into_iter()
-                .filter_map(|(k, v)| {
-                    let v = to_datastore_value(v);
-                    if v.is_none() {
-                        any_invalid = true;
-                    }
-                    v.map(|v| (k, v))
-                })
-                .collect::<HashMap<_, _>>();
-            Some(google_datastore1::Value {
-                entity_value: Some(google_datastore1::Entity {
-                    properties: Some(xs),
-                    key: None,
-                }),
-                ..google_datastore1::Value::default()
-            })
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        Ok(variant_value(variant, value.serialize(self)?))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: None,
+            values: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            values: Vec::with_capacity(len),
+        })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeEntity, Error> {
+        Ok(SerializeEntity {
+            variant: None,
+            properties: HashMap::new(),
+            next_key: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeEntity, Error> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeEntity, Error> {
+        Ok(SerializeEntity {
+            variant: Some(variant),
+            properties: HashMap::new(),
+            next_key: None,
+        })
+    }
+}
+
+struct SerializeArray {
+    /// Set for tuple variants.
+    variant: Option<&'static str>,
+    values: Vec<google_datastore1::Value>,
+}
+
+impl SerializeArray {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.values.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn finish(self) -> google_datastore1::Value {
+        match self.variant {
+            Some(variant) => variant_value(variant, array_value(self.values)),
+            None => array_value(self.values),
+        }
+    }
+}
+
+impl serde::ser::SerializeSeq for SerializeArray {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl serde::ser::SerializeTuple for SerializeArray {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl serde::ser::SerializeTupleStruct for SerializeArray {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl serde::ser::SerializeTupleVariant for SerializeArray {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+struct SerializeEntity {
+    /// Set for struct variants.
+    variant: Option<&'static str>,
+    properties: HashMap<String, google_datastore1::Value>,
+    next_key: Option<String>,
+}
+
+impl SerializeEntity {
+    fn insert<T: ?Sized + Serialize>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        self.properties.insert(key, value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn finish(self) -> google_datastore1::Value {
+        match self.variant {
+            Some(variant) => variant_value(variant, entity_value(self.properties)),
+            None => entity_value(self.properties),
         }
     }
 }
 
-pub fn from_datastore_value<T: serde::de::DeserializeOwned>(value: google_datastore1::Value) -> Option<T> {
-    let mut serde_value: serde_json::Value;
-    if let Some(xs) = value.entity_value {
-        let mut any_invalid = false;
-        let xs = xs
-            .properties
-            .unwrap_or(HashMap::default())
-            .into_iter()
-            .filter_map(|(k, v)| {
-                let v = from_datastore_value(v);
-                if v.is_none() {
-                    any_invalid = true;
+impl serde::ser::SerializeMap for SerializeEntity {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        // Like `serde_json`, accept string and integer keys.
+        let key = key.serialize(ValueSerializer)?;
+        let key = key.string_value.or(key.integer_value).ok_or_else(|| {
+            Error::Serialization {msg: String::from("map keys must be strings or integers")}
+        })?;
+        self.next_key = Some(key);
+        Ok(())
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.next_key.take().ok_or_else(|| {
+            Error::Serialization {msg: String::from("map value without a key")}
+        })?;
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl serde::ser::SerializeStruct for SerializeEntity {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.insert(key.to_owned(), value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl serde::ser::SerializeStructVariant for SerializeEntity {
+    type Ok = google_datastore1::Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.insert(key.to_owned(), value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.finish())
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// FROM DATASTORE
+///////////////////////////////////////////////////////////////////////////////
+
+/// A step into a nested Datastore value.
+#[derive(Debug, Clone)]
+enum PathSegment {
+    Property(String),
+    Index(usize),
+}
+
+/// Renders a path such as `address.zip[2]`.
+fn format_path(path: &[PathSegment]) -> String {
+    let mut result = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Property(name) => {
+                if !result.is_empty() {
+                    result.push('.');
                 }
-                v.map(|v| (k, v))
-            })
-            .collect::<serde_json::Map<_, _>>();
-        if !any_invalid {
-            serde_value = serde_json::Value::Object(xs);
-        } else {
-            return None;
-        }
-    } else if let Some(xs) = value.timestamp_value {
-        serde_value = serde_json::Value::String(xs);
-    } else if let Some(xs) = value.geo_point_value {
-        serde_value = unimplemented!();
-    } else if let Some(xs) = value.blob_value {
-        serde_value = unimplemented!();
+                result.push_str(name);
+            }
+            PathSegment::Index(ix) => result.push_str(&format!("[{}]", ix)),
+        }
+    }
+    result
+}
+
+fn lookup<'a>(value: &'a google_datastore1::Value, path: &[PathSegment]) -> Option<&'a google_datastore1::Value> {
+    path.iter().try_fold(value, |value, segment| {
+        match segment {
+            PathSegment::Property(name) => value.entity_value.as_ref()?.properties.as_ref()?.get(name),
+            PathSegment::Index(ix) => value.array_value.as_ref()?.values.as_ref()?.get(*ix),
+        }
+    })
+}
+
+/// Describes what Datastore held, for error messages.
+fn describe(value: Option<&google_datastore1::Value>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return String::from("nothing"),
+    };
+    if let Some(xs) = &value.entity_value {
+        let len = xs.properties.as_ref().map(|xs| xs.len()).unwrap_or(0);
+        format!("entity_value with {} properties", len)
+    } else if let Some(x) = &value.timestamp_value {
+        format!("timestamp_value {:?}", x)
+    } else if let Some(x) = &value.geo_point_value {
+        format!(
+            "geo_point_value ({}, {})",
+            x.latitude.unwrap_or(0.0),
+            x.longitude.unwrap_or(0.0),
+        )
+    } else if let Some(x) = &value.blob_value {
+        match decode_blob(x) {
+            Some(xs) => format!("blob_value of {} bytes", xs.len()),
+            None => String::from("blob_value with malformed base64"),
+        }
+    } else if let Some(x) = value.double_value {
+        format!("double_value {}", x)
+    } else if let Some(x) = &value.string_value {
+        format!("string_value {:?}", x)
+    } else if let Some(x) = &value.key_value {
+        match key::from_datastore_key(x.clone()) {
+            Some(key) => format!("key_value {}", key),
+            None => String::from("incomplete key_value"),
+        }
+    } else if let Some(x) = value.boolean_value {
+        format!("boolean_value {}", x)
+    } else if let Some(xs) = &value.array_value {
+        let len = xs.values.as_ref().map(|xs| xs.len()).unwrap_or(0);
+        format!("array_value with {} elements", len)
+    } else if let Some(x) = &value.integer_value {
+        format!("integer_value {}", x)
+    } else {
+        String::from("null_value")
+    }
+}
+
+/// Blobs are base64-encoded; accept the URL-safe alphabet as well.
+fn decode_blob(value: &str) -> Option<Vec<u8>> {
+    base64::decode(value)
+        .or_else(|_| base64::decode_config(value, base64::URL_SAFE))
+        .ok()
+}
+
+/// Converts a Datastore value into the JSON that serde deserializes from.
+/// Never panics, whatever combination of fields is set.
+///
+/// On failure, returns the path to the offending value and the reason.
+fn to_json_value(
+    value: &google_datastore1::Value,
+    path: &mut Vec<PathSegment>,
+) -> Result<serde_json::Value, (Vec<PathSegment>, String)> {
+    if let Some(xs) = &value.entity_value {
+        let mut result = serde_json::Map::new();
+        for (k, v) in xs.properties.iter().flatten() {
+            path.push(PathSegment::Property(k.clone()));
+            result.insert(k.clone(), to_json_value(v, path)?);
+            path.pop();
+        }
+        Ok(serde_json::Value::Object(result))
+    } else if let Some(xs) = &value.timestamp_value {
+        Ok(serde_json::Value::String(xs.clone()))
+    } else if let Some(xs) = &value.geo_point_value {
+        // Zero coordinates are omitted from responses.
+        Ok(serde_json::json!({
+            "latitude": xs.latitude.unwrap_or(0.0),
+            "longitude": xs.longitude.unwrap_or(0.0),
+        }))
+    } else if let Some(xs) = &value.blob_value {
+        // As a sequence of bytes, which `Vec<u8>`, `serde_bytes` and
+        // `bytes::Bytes` all deserialize from.
+        match decode_blob(xs) {
+            Some(xs) => Ok(serde_json::Value::Array(xs.into_iter().map(serde_json::Value::from).collect())),
+            None => Err((path.clone(), String::from("malformed base64 in blob_value"))),
+        }
     } else if let Some(xs) = value.double_value {
-        serde_value = serde_json::Value::Number(serde_json::Number::from_f64(xs)?);
-    } else if let Some(xs) = value.meaning {
-        serde_value = unimplemented!();
-    } else if let Some(xs) = value.exclude_from_indexes {
-        serde_value = unimplemented!();
-    } else if let Some(xs) = value.string_value {
-        serde_value = serde_json::Value::String(xs);
-    } else if let Some(xs) = value.key_value {
-        serde_value = unimplemented!();
+        match serde_json::Number::from_f64(xs) {
+            Some(number) => Ok(serde_json::Value::Number(number)),
+            None => Err((path.clone(), String::from("non-finite double_value"))),
+        }
+    } else if let Some(xs) = &value.string_value {
+        Ok(serde_json::Value::String(xs.clone()))
+    } else if let Some(xs) = &value.key_value {
+        // As the list of path elements `Key` deserializes from.
+        match key::from_datastore_key(xs.clone()) {
+            Some(key) => serde_json::to_value(key::to_serde_path(&key))
+                .map_err(|e| (path.clone(), e.to_string())),
+            None => Err((path.clone(), String::from("incomplete or malformed key_value"))),
+        }
     } else if let Some(xs) = value.boolean_value {
-        serde_value = serde_json::Value::Bool(xs);
-    } else if let Some(xs) = value.array_value {
-        let mut any_invalid = false;
-        let xs = xs
-            .values
-            .unwrap_or(Vec::default())
-            .into_iter()
-            .filter_map(|x| {
-                let x = from_datastore_value(x);
-                any_invalid = true;
-                x
-            })
-            .collect::<Vec<_>>();
-        if !any_invalid {
-            serde_value = serde_json::Value::Array(xs);
-        } else {
-            return None;
-        }
-    } else if let Some(xs) = value.integer_value {
-        let as_u64: Option<u64> = FromStr::from_str(&xs).ok();
-        let as_i64: Option<i64> = FromStr::from_str(&xs).ok();
-        let number: serde_json::Number = as_u64
-            .map(|x| From::from(x))
-            .or(as_i64.map(|x| From::from(x)))?;
-        serde_value = serde_json::Value::Number(number);
-    } else if let Some(xs) = value.null_value {
-        serde_value = serde_json::Value::Null;
+        Ok(serde_json::Value::Bool(xs))
+    } else if let Some(xs) = &value.array_value {
+        let mut result = Vec::new();
+        for (ix, x) in xs.values.iter().flatten().enumerate() {
+            path.push(PathSegment::Index(ix));
+            result.push(to_json_value(x, path)?);
+            path.pop();
+        }
+        Ok(serde_json::Value::Array(result))
+    } else if let Some(xs) = &value.integer_value {
+        let as_u64: Option<u64> = FromStr::from_str(xs).ok();
+        let as_i64: Option<i64> = FromStr::from_str(xs).ok();
+        match as_u64.map(From::from).or_else(|| as_i64.map(From::from)) {
+            Some(number) => Ok(serde_json::Value::Number(number)),
+            None => Err((path.clone(), String::from("malformed integer_value"))),
+        }
     } else {
-        serde_value = serde_json::Value::Null;
+        // `null_value`, or no value at all. `meaning` and
+        // `exclude_from_indexes` only annotate the value, so are ignored.
+        Ok(serde_json::Value::Null)
     }
-    serde_json::from_value(serde_value)
-        .map_err(|e| {
-            // eprintln!("error serde_json::from_value: {:#?}", e);
-            e
-        })
-        .ok()
 }
 
+/// Deserializes `value`, reporting failures as `Error::InvalidEntity`.
+fn deserialize<T: serde::de::DeserializeOwned>(
+    value: &google_datastore1::Value,
+    key: Option<KeyPath>,
+) -> Result<T, Error> {
+    let invalid = |path: Vec<PathSegment>, msg: String| {
+        Error::InvalidEntity(Box::new(InvalidEntity {
+            key: key.clone(),
+            path: format_path(&path),
+            found: describe(lookup(value, &path)),
+            msg,
+        }))
+    };
+    let json = to_json_value(value, &mut Vec::new())
+        .map_err(|(path, msg)| invalid(path, msg))?;
+    serde_path_to_error::deserialize(json).map_err(|e| {
+        let path = e
+            .path()
+            .iter()
+            .filter_map(|segment| match segment {
+                serde_path_to_error::Segment::Map {key} => Some(PathSegment::Property(key.clone())),
+                serde_path_to_error::Segment::Enum {variant} => Some(PathSegment::Property(variant.clone())),
+                serde_path_to_error::Segment::Seq {index} => Some(PathSegment::Index(*index)),
+                serde_path_to_error::Segment::Unknown => None,
+            })
+            .collect();
+        invalid(path, e.into_inner().to_string())
+    })
+}
+
+/// Fails with `Error::InvalidEntity` if `value` does not match `T`.
+pub(crate) fn from_datastore_value<T: serde::de::DeserializeOwned>(value: google_datastore1::Value) -> Result<T, Error> {
+    deserialize(&value, None)
+}
 
-pub fn from_datastore_entity<T: serde::de::DeserializeOwned>(value: google_datastore1::Entity) -> Option<T> {
+/// Like `from_datastore_value`, reporting the entity's key on failure.
+pub(crate) fn from_datastore_entity<T: serde::de::DeserializeOwned>(value: google_datastore1::Entity) -> Result<T, Error> {
+    let key = value.key.clone().and_then(key::from_datastore_key);
     let value = google_datastore1::Value {
         entity_value: Some(value),
         ..Default::default()
     };
-    from_datastore_value(value)
+    deserialize(&value, key)
 }
 
 
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
 
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use proptest::prelude::*;
+    use proptest::collection::{hash_map, vec};
+    use proptest::option;
+    use serde::{Serialize, Deserialize};
+    use google_datastore1::{ArrayValue, Entity, LatLng, Value};
+    use crate::db::EntityKey;
+    use crate::geo::GeoPoint;
+    use crate::key::{Key, PathElement};
+    use super::{from_datastore_entity, from_datastore_value, to_datastore_value};
+
+    ///////////////////////////////////////////////////////////////////////////
+    // STRATEGIES
+    ///////////////////////////////////////////////////////////////////////////
+
+    /// Mostly well-formed integers, sometimes garbage.
+    fn integer_string() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => any::<i64>().prop_map(|x| x.to_string()),
+            1 => any::<u64>().prop_map(|x| x.to_string()),
+            1 => any::<String>(),
+        ]
+    }
+
+    /// Mostly valid base64, sometimes garbage.
+    fn blob_string() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => vec(any::<u8>(), 0..64).prop_map(|xs| base64::encode(&xs)),
+            1 => any::<String>(),
+        ]
+    }
+
+    fn key() -> impl Strategy<Value = google_datastore1::Key> {
+        let element = (option::of("[A-Za-z]{1,8}"), option::of(any::<String>()), option::of(integer_string()))
+            .prop_map(|(kind, name, id)| google_datastore1::PathElement {kind, name, id});
+        option::of(vec(element, 0..4)).prop_map(|path| google_datastore1::Key {
+            path,
+            partition_id: None,
+        })
+    }
+
+    /// A value with any combination of fields set, including none or several.
+    fn leaf() -> impl Strategy<Value = Value> {
+        let geo_point = (option::of(any::<f64>()), option::of(any::<f64>()))
+            .prop_map(|(latitude, longitude)| LatLng {latitude, longitude});
+        (
+            (
+                option::of(any::<String>()),
+                option::of(geo_point),
+                option::of(blob_string()),
+                option::of(any::<f64>()),
+                option::of(any::<i32>()),
+                option::of(any::<bool>()),
+            ),
+            (
+                option::of(any::<String>()),
+                option::of(key()),
+                option::of(any::<bool>()),
+                option::of(integer_string()),
+                option::of(Just(String::from("NULL_VALUE"))),
+            ),
+        ).prop_map(|(
+            (timestamp_value, geo_point_value, blob_value, double_value, meaning, exclude_from_indexes),
+            (string_value, key_value, boolean_value, integer_value, null_value),
+        )| {
+            Value {
+                entity_value: None,
+                timestamp_value,
+                geo_point_value,
+                blob_value,
+                double_value,
+                meaning,
+                exclude_from_indexes,
+                string_value,
+                key_value,
+                boolean_value,
+                array_value: None,
+                integer_value,
+                null_value,
+            }
+        })
+    }
+
+    fn value() -> impl Strategy<Value = Value> {
+        leaf().prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..8).prop_map(|xs| Value {
+                    array_value: Some(ArrayValue {values: Some(xs)}),
+                    ..Value::default()
+                }),
+                (option::of(key()), hash_map("[a-z]{1,8}", inner, 0..8)).prop_map(|(key, xs)| Value {
+                    entity_value: Some(Entity {
+                        properties: Some(xs),
+                        key,
+                    }),
+                    ..Value::default()
+                }),
+            ]
+        })
+    }
+
+    /// JSON that survives a round trip: numbers are integers or finite doubles.
+    fn json() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<u64>().prop_map(serde_json::Value::from),
+            any::<f64>()
+                .prop_filter("finite", |x| x.is_finite())
+                .prop_map(serde_json::Value::from),
+            any::<String>().prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 0..8).prop_map(serde_json::Value::Array),
+                hash_map("[a-z]{1,8}", inner, 0..8)
+                    .prop_map(|xs| serde_json::Value::Object(xs.into_iter().collect())),
+            ]
+        })
+    }
+
+
+    ///////////////////////////////////////////////////////////////////////////
+    // MODELS
+    ///////////////////////////////////////////////////////////////////////////
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct User {
+        name: String,
+    }
+
+    impl EntityKey for User {
+        fn entity_kind_key() -> String {
+            String::from("User")
+        }
+        fn entity_name_key(&self) -> String {
+            self.name.clone()
+        }
+    }
+
+    /// Exercises every property type the crate maps specially.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Store {
+        name: String,
+        rank: i32,
+        score: f64,
+        open: bool,
+        tags: Vec<String>,
+        owner: Key<User>,
+        location: GeoPoint,
+        #[serde(with = "serde_bytes")]
+        logo: Vec<u8>,
+        parent: Option<Box<Store>>,
+        extra: HashMap<String, u64>,
+    }
+
+    struct Org;
+
+    impl EntityKey for Org {
+        fn entity_kind_key() -> String {
+            String::from("Org")
+        }
+        fn entity_name_key(&self) -> String {
+            String::new()
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Logo {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Place {
+        location: GeoPoint,
+    }
+
+
+    ///////////////////////////////////////////////////////////////////////////
+    // VALUE TYPES
+    ///////////////////////////////////////////////////////////////////////////
+
+    #[test]
+    fn blobs_round_trip() {
+        let logo = Logo {data: (0..=255).collect()};
+        let value = to_datastore_value(&logo).unwrap();
+        let property = &value.entity_value.as_ref().and_then(|x| x.properties.as_ref()).unwrap()["data"];
+        assert_eq!(property.blob_value.as_deref(), Some(base64::encode(&logo.data).as_str()));
+        assert_eq!(property.exclude_from_indexes, None);
+        assert_eq!(from_datastore_value::<Logo>(value).unwrap(), logo);
+    }
+
+    #[test]
+    fn large_blobs_are_not_indexed() {
+        let logo = Logo {data: vec![7; 2000]};
+        let value = to_datastore_value(&logo).unwrap();
+        let property = &value.entity_value.as_ref().and_then(|x| x.properties.as_ref()).unwrap()["data"];
+        assert_eq!(property.exclude_from_indexes, Some(true));
+        assert_eq!(from_datastore_value::<Logo>(value).unwrap(), logo);
+    }
+
+    #[test]
+    fn geo_points_round_trip() {
+        let place = Place {location: GeoPoint::new(52.52, 13.405)};
+        let value = to_datastore_value(&place).unwrap();
+        let property = &value.entity_value.as_ref().and_then(|x| x.properties.as_ref()).unwrap()["location"];
+        let point = property.geo_point_value.as_ref().unwrap();
+        assert_eq!((point.latitude, point.longitude), (Some(52.52), Some(13.405)));
+        assert_eq!(from_datastore_value::<Place>(value).unwrap(), place);
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let key = Key::<User>::with_ancestors(vec![PathElement::new("Org", "acme")], "ada");
+        let value = to_datastore_value(&key).unwrap();
+        let path = value.key_value.as_ref().and_then(|x| x.path.as_ref()).unwrap();
+        assert_eq!(path[1].kind.as_deref(), Some("User"));
+        assert_eq!(path[1].name.as_deref(), Some("ada"));
+        assert_eq!(from_datastore_value::<Key<User>>(value.clone()).unwrap(), key);
+        // The kind is checked when reading.
+        assert!(from_datastore_value::<Key<Org>>(value).is_err());
+    }
+
+    #[test]
+    fn keys_are_described_as_paths() {
+        let key = Key::<User>::with_ancestors(vec![PathElement::new("Org", "acme")], "ada");
+        let value = to_datastore_value(&key).unwrap();
+        let e = from_datastore_value::<u32>(value).unwrap_err();
+        assert!(e.to_string().ends_with("(datastore held key_value Org:acme/User:ada)"), "{}", e);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // PROPERTIES
+    ///////////////////////////////////////////////////////////////////////////
+
+    // Conversion must be total: any value Datastore could return converts
+    // into an `Ok` or an `Err`, never a panic.
+
+    proptest! {
+        #[test]
+        fn from_datastore_value_never_panics(value in value()) {
+            let _ = from_datastore_value::<serde_json::Value>(value.clone());
+            let _ = from_datastore_value::<Store>(value.clone());
+            let _ = from_datastore_value::<Key<User>>(value.clone());
+            let _ = from_datastore_value::<GeoPoint>(value.clone());
+            let _ = from_datastore_value::<Vec<u8>>(value);
+        }
+
+        #[test]
+        fn from_datastore_entity_never_panics(value in value()) {
+            let entity = value.entity_value.unwrap_or_default();
+            if let Err(e) = from_datastore_entity::<Store>(entity) {
+                // Formatting the error must not panic either.
+                let _ = e.to_string();
+            }
+        }
+
+        #[test]
+        fn json_round_trips(json in json()) {
+            let value = to_datastore_value(&json).unwrap();
+            prop_assert_eq!(from_datastore_value::<serde_json::Value>(value).unwrap(), json);
+        }
+    }
+}
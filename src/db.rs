@@ -1,16 +1,29 @@
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::path::PathBuf;
 use std::string::ToString;
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::aggregation;
+use crate::batch;
 use crate::convert;
 use crate::auth;
+use crate::key;
+use crate::transaction;
 
 use google_datastore1::RunQueryRequest;
 
 
+pub use crate::aggregation::{AggregateValue, Aggregation};
 pub use crate::auth::Auth;
+pub use crate::batch::{ChunkResult, WriteBatch, MAX_MUTATIONS_PER_COMMIT};
+pub use crate::error::{Error, InvalidEntity, Status};
+pub use crate::geo::GeoPoint;
+pub use crate::gql::GqlQuery;
+pub use crate::key::{Key, KeyId, KeyPath, PathElement};
+pub use crate::query::{Cursor, Direction, Filter, MoreResults, Page, Pages, PropertyOp, Query, QueryIter};
+pub use crate::timestamp::Timestamp;
+pub use crate::transaction::{ReadOnlyTransaction, Transaction};
 
 ///////////////////////////////////////////////////////////////////////////////
 // HELPERS
@@ -18,23 +31,94 @@ pub use crate::auth::Auth;
 
 pub trait EntityKey {
     fn entity_kind_key() -> String;
+    /// The key name of this entity, used by the default `entity_key_id`.
     fn entity_name_key(&self) -> String;
+    /// The key identifier of this entity. Override it for entities keyed by
+    /// numeric ids.
+    ///
+    /// Returning `None` marks the key as incomplete: `insert` then lets
+    /// Datastore allocate a numeric id. Defaults to
+    /// `Some(KeyId::Name(self.entity_name_key()))`.
+    fn entity_key_id(&self) -> Option<KeyId> {
+        Some(KeyId::Name(self.entity_name_key()))
+    }
+    /// The key path of the parent entity, outermost ancestor first.
+    ///
+    /// Defaults to no ancestors, i.e. a root entity.
+    fn entity_ancestor_keys(&self) -> Vec<PathElement> {
+        Vec::new()
+    }
+}
+
+
+
+
+/// An entity together with its version, which changes on every write.
+///
+/// Pass the version back to e.g. `update_if_version` to detect lost updates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: i64,
+}
+
+
+/// What Datastore reports back from a commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitResult {
+    /// One per mutation, in the order they were sent.
+    pub mutation_results: Vec<MutationResult>,
+    /// Number of index entries written, e.g. for cost tracking.
+    pub index_updates: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutationResult {
+    /// The key of the written entity. Datastore only reports it for
+    /// allocated ids, but `insert` always fills it in.
+    pub key: Option<KeyPath>,
+    /// The entity's version after the write, see `Versioned`.
+    pub version: Option<i64>,
+    /// Whether the mutation's `base_version` did not match.
+    pub conflict_detected: bool,
+}
+
+impl CommitResult {
+    fn from_datastore(response: google_datastore1::CommitResponse) -> Self {
+        let mutation_results = response.mutation_results
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                MutationResult {
+                    key: x.key.and_then(key::from_datastore_key),
+                    version: x.version.and_then(|x| x.parse().ok()),
+                    conflict_detected: x.conflict_detected.unwrap_or(false),
+                }
+            })
+            .collect();
+        CommitResult {
+            mutation_results,
+            index_updates: response.index_updates.unwrap_or(0),
+        }
+    }
 }
 
 
-#[derive(Debug)]
-pub enum Error {
-    Serialization {
-        msg: String,
-    },
-    Deserialization {
-        msg: String,
-    },
-    DatabaseResponse(google_datastore1::Error),
-    NoPayload,
+/// Consistency level of lookups and queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadConsistency {
+    Strong,
+    Eventual,
 }
 
-unsafe impl Send for Error {}
+impl ReadConsistency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReadConsistency::Strong => "STRONG",
+            ReadConsistency::Eventual => "EVENTUAL",
+        }
+    }
+}
 
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -43,10 +127,20 @@ unsafe impl Send for Error {}
 
 type Handle = google_datastore1::Datastore<hyper::Client, auth::Auth>;
 
+/// Maximum number of keys Datastore accepts in a single lookup.
+const MAX_LOOKUP_KEYS: usize = 1000;
+
 #[derive(Clone)]
 pub struct DatastoreClient {
     handle: Rc<Handle>,
+    /// For endpoints not covered by `google_datastore1`.
+    http: Rc<hyper::Client>,
+    auth: Auth,
     project_id: String,
+    namespace: Option<String>,
+    read_consistency: Option<ReadConsistency>,
+    /// Set on the clients owned by a `Transaction`; reads then go through it.
+    transaction: Option<String>,
 }
 
 impl DatastoreClient {
@@ -61,261 +155,796 @@ impl DatastoreClient {
         let client = hyper::Client::with_connector(
             hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
         );
-        let hub = google_datastore1::Datastore::new(client, auth);
+        let http = hyper::Client::with_connector(
+            hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new())
+        );
+        let hub = google_datastore1::Datastore::new(client, auth.clone());
         Ok(DatastoreClient {
             handle: Rc::new(hub),
+            http: Rc::new(http),
+            auth,
             project_id,
+            namespace: None,
+            read_consistency: None,
+            transaction: None,
         })
     }
-    pub fn insert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
-            })
-            .and_then(|x| x.properties)
-            .ok_or(Error::Serialization {
-                msg: String::from("expecting struct/map like input")
-            })?;
-        let entity = google_datastore1::Entity {
-            properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
+    /// A client whose reads, writes and queries all target the given
+    /// namespace, e.g. one per tenant. Shares the underlying connection.
+    pub fn with_namespace<N: ToString>(&self, namespace: N) -> Self {
+        DatastoreClient {
+            namespace: Some(namespace.to_string()),
+            ..self.clone()
+        }
+    }
+    /// The namespace of this client, `None` for the default namespace.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+    /// A client whose lookups and queries use the given consistency level,
+    /// e.g. `db.with_read_consistency(ReadConsistency::Eventual).list()`.
+    ///
+    /// Without one, Datastore defaults to strong consistency for lookups and
+    /// ancestor queries, and eventual consistency for other queries.
+    pub fn with_read_consistency(&self, read_consistency: ReadConsistency) -> Self {
+        DatastoreClient {
+            read_consistency: Some(read_consistency),
+            ..self.clone()
+        }
+    }
+    /// The key of the new entity, including the allocated id if `value`
+    /// had an incomplete key, is in `mutation_results[0].key`.
+    pub fn insert<T: Serialize + EntityKey>(&self, value: T) -> Result<CommitResult, Error> {
+        let request_key = KeyPath::of(&value);
+        let entity = self.to_entity(value)?;
+        let mut result = self.commit(batch::insert_mutation(entity))?;
+        // Datastore only echoes the key back when it allocated the id.
+        for x in result.mutation_results.iter_mut() {
+            if x.key.is_none() {
+                x.key = request_key.clone();
+            }
+        }
+        Ok(result)
+    }
+    pub fn upsert<T: Serialize + EntityKey>(&self, value: T) -> Result<CommitResult, Error> {
+        let entity = self.to_entity(value)?;
+        self.commit(batch::upsert_mutation(entity))
+    }
+    pub fn update<T: Serialize + EntityKey>(&self, value: T) -> Result<CommitResult, Error> {
+        let entity = self.to_entity(value)?;
+        self.commit(batch::update_mutation(entity))
+    }
+    /// Updates `value` only if the stored entity still has the given
+    /// version, failing with `Error::VersionConflict` otherwise.
+    pub fn update_if_version<T: Serialize + EntityKey>(&self, value: T, version: i64) -> Result<CommitResult, Error> {
+        let entity = self.to_entity(value)?;
+        self.commit_versioned(batch::with_base_version(batch::update_mutation(entity), version))
+    }
+    /// Like `update_if_version`, upserting.
+    pub fn upsert_if_version<T: Serialize + EntityKey>(&self, value: T, version: i64) -> Result<CommitResult, Error> {
+        let entity = self.to_entity(value)?;
+        self.commit_versioned(batch::with_base_version(batch::upsert_mutation(entity), version))
+    }
+    /// Deletes the entity at `key` only if it still has the given version,
+    /// failing with `Error::VersionConflict` otherwise.
+    pub fn delete_if_version(&self, key: KeyPath, version: i64) -> Result<CommitResult, Error> {
+        let key = self.to_key(key.into_datastore_path());
+        self.commit_versioned(batch::with_base_version(batch::delete_mutation(key), version))
+    }
+    /// Like `get`, also returning the entity's current version.
+    pub fn get_versioned<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<Versioned<T>, Error> {
+        self.get_versioned_with_ancestors(&[], key)
+    }
+    /// Like `get_with_ancestors`, also returning the entity's current version.
+    pub fn get_versioned_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<Versioned<T>, Error> {
+        let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key);
+        let result = self.lookup_one(&key)?
+            .ok_or_else(|| Error::EntityNotFound {key: key.clone()})?;
+        let version = result.version
+            .and_then(|x| x.parse().ok())
+            .ok_or(Error::NoPayload)?;
+        let payload = result.entity.ok_or(Error::NoPayload)?;
+        let value = convert::from_datastore_entity(payload)?;
+        Ok(Versioned {value, version})
+    }
+    /// Fails with `Error::EntityNotFound` if there is no entity at `key`;
+    /// see `try_get` to get an `Option` instead.
+    pub fn get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<T, Error> {
+        self.get_with_ancestors(&[], key)
+    }
+    /// Like `get`, for entities stored under the given ancestor path
+    /// (outermost ancestor first).
+    pub fn get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<T, Error> {
+        let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key);
+        self.try_get_by_path(&key)?
+            .ok_or(Error::EntityNotFound {key})
+    }
+    /// Like `get`, returning `Ok(None)` if there is no entity at `key`.
+    pub fn try_get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<Option<T>, Error> {
+        self.try_get_with_ancestors(&[], key)
+    }
+    /// Like `try_get`, for entities stored under the given ancestor path.
+    pub fn try_get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<Option<T>, Error> {
+        let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key);
+        self.try_get_by_path(&key)
+    }
+    /// Resolves a key reference, failing with `Error::EntityNotFound` if
+    /// there is no entity at `key`.
+    pub fn get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<T, Error> {
+        self.try_get_by_key(key)?
+            .ok_or_else(|| Error::EntityNotFound {key: key.path().clone()})
+    }
+    /// Like `get_by_key`, returning `Ok(None)` if there is no entity at `key`.
+    pub fn try_get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<Option<T>, Error> {
+        self.try_get_by_path(key.path())
+    }
+    /// Whether a root entity of kind `T` exists at `key`.
+    pub fn exists<T: EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<bool, Error> {
+        let key = KeyPath::new(Vec::new(), T::entity_kind_key(), key);
+        self.exists_by_path(&key)
+    }
+    pub fn exists_by_path(&self, key: &KeyPath) -> Result<bool, Error> {
+        self.lookup_one(key).map(|x| x.is_some())
+    }
+    /// Fetches many root entities of kind `T` in as few round trips as
+    /// possible. Results are in the order of `keys`, `None` for keys that
+    /// do not exist.
+    pub fn get_many<T, K, I>(&self, keys: I) -> Result<Vec<Option<T>>, Error>
+    where
+        T: DeserializeOwned + EntityKey,
+        K: Into<KeyId>,
+        I: IntoIterator<Item = K>,
+    {
+        let keys = keys
+            .into_iter()
+            .map(|x| KeyPath::new(Vec::new(), T::entity_kind_key(), x))
+            .collect::<Vec<_>>();
+        self.get_many_by_path(&keys)
+    }
+    /// Like `get_many`, for arbitrary (e.g. child entity) keys.
+    pub fn get_many_by_path<T: DeserializeOwned>(&self, keys: &[KeyPath]) -> Result<Vec<Option<T>>, Error> {
+        // Datastore rejects lookups that repeat a key, so send each once.
+        let mut seen = HashSet::new();
+        let unique = keys
+            .iter()
+            .filter(|x| seen.insert(*x))
+            .collect::<Vec<_>>();
+        let mut found = HashMap::new();
+        for chunk in unique.chunks(MAX_LOOKUP_KEYS) {
+            let mut pending = chunk
+                .iter()
+                .map(|x| self.to_key((*x).clone().into_datastore_path()))
+                .collect::<Vec<_>>();
+            // Datastore may defer some keys (e.g. when the response would be
+            // too large); request those again until none are left.
+            while !pending.is_empty() {
+                let response = self.lookup(pending)?;
+                for entity in response.found.unwrap_or_default().into_iter().filter_map(|x| x.entity) {
+                    let key = entity.key
+                        .clone()
+                        .and_then(key::from_datastore_key)
+                        .ok_or(Error::NoPayload)?;
+                    found.insert(key, entity);
+                }
+                pending = response.deferred.unwrap_or_default();
+            }
+        }
+        keys
+            .iter()
+            .map(|key| {
+                match found.get(key).cloned() {
+                    Some(entity) => {
+                        convert::from_datastore_entity(entity).map(Some)
                     }
-                ]),
-                partition_id: None
+                    None => Ok(None),
+                }
             })
-        };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: Some(entity),
-                    delete: None,
-                    update: None,
-                    base_version: None,
-                    upsert: None
+            .collect()
+    }
+    pub fn list<T: DeserializeOwned + EntityKey>(&self) -> Result<Vec<T>, Error> {
+        self.query(&Query::new())
+    }
+    /// Runs a filtered, ordered and/or limited query over kind `T`,
+    /// following cursors until all matching entities are fetched.
+    pub fn query<T: DeserializeOwned + EntityKey>(&self, query: &Query<T>) -> Result<Vec<T>, Error> {
+        self.query_as::<T, T>(query)
+    }
+    /// Like `query`, deserializing the results into `P` instead of `T`.
+    ///
+    /// Meant for projection queries, e.g. listing only the `name` and
+    /// `title` of each `TodoItem`:
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # pub struct TodoItem { pub name: String, pub title: String }
+    /// # impl ddb::EntityKey for TodoItem {
+    /// #     fn entity_kind_key() -> String { String::from("TodoItem") }
+    /// #     fn entity_name_key(&self) -> String { self.name.clone() }
+    /// # }
+    /// #[derive(Deserialize)]
+    /// pub struct TodoTitle {
+    ///     pub name: String,
+    ///     pub title: String,
+    /// }
+    /// let db = ddb::DatastoreClient::new().unwrap();
+    /// let query = ddb::Query::<TodoItem>::new().project(&["name", "title"]);
+    /// let titles: Vec<TodoTitle> = db.query_as(&query).unwrap();
+    /// ```
+    pub fn query_as<T: EntityKey, P: DeserializeOwned>(&self, query: &Query<T>) -> Result<Vec<P>, Error> {
+        let mut query = query.clone();
+        let mut results = Vec::new();
+        loop {
+            let page = self.query_entity_page(&query)?;
+            let is_last = query.advance(&page);
+            for entity in page.results {
+                results.push(convert::from_datastore_entity(entity)?);
+            }
+            if is_last {
+                return Ok(results);
+            }
+        }
+    }
+    /// Fetches a single batch of results. Use `Page::cursor` with
+    /// `Query::start_cursor` to request the next one, reducing any offset
+    /// by `Page::skipped_results`.
+    pub fn query_page<T: DeserializeOwned + EntityKey>(&self, query: &Query<T>) -> Result<Page<T>, Error> {
+        let mut page = self.query_entity_page(query)?;
+        let results = std::mem::take(&mut page.results)
+            .into_iter()
+            .map(convert::from_datastore_entity)
+            .collect::<Result<Vec<T>, Error>>()?;
+        Ok(page.with_results(results))
+    }
+    /// Iterates over the pages of a query, fetching each batch on demand.
+    pub fn query_pages<T: DeserializeOwned + EntityKey>(&self, query: Query<T>) -> Pages<T> {
+        Pages::new(self.clone(), query)
+    }
+    /// Lazily iterates over the results of a query, fetching further
+    /// batches only as they are consumed. Entities that fail to deserialize
+    /// are yielded as errors.
+    pub fn query_iter<T: DeserializeOwned + EntityKey>(&self, query: Query<T>) -> QueryIter<T> {
+        QueryIter::new(self.clone(), query)
+    }
+    /// Runs a GQL query, following cursors until all results are fetched.
+    ///
+    /// Results can be deserialized into any `DeserializeOwned` type, e.g.
+    /// `serde_json::Value` for entities without a dedicated struct.
+    pub fn gql_query<T: DeserializeOwned>(&self, query: &GqlQuery) -> Result<Vec<T>, Error> {
+        let mut response = self.run_query_request(RunQueryRequest {
+            query: None,
+            partition_id: self.partition_id(),
+            gql_query: Some(query.to_datastore_gql()?),
+            read_options: self.read_options(),
+        })?;
+        // Later batches continue from the structured query Datastore parsed
+        // the GQL string into, which only the first response carries.
+        let mut next = response.query.take();
+        let mut results = Vec::new();
+        loop {
+            let batch = response.batch.ok_or(Error::NoPayload)?;
+            let entities = batch.entity_results
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|x| x.entity)
+                .collect::<Vec<_>>();
+            let page = Page::new(entities, &batch);
+            let is_last = match &mut next {
+                Some(next) => page.advance_datastore_query(next),
+                None => page.is_last(None),
+            };
+            for entity in page.results {
+                results.push(convert::from_datastore_entity(entity)?);
+            }
+            if is_last {
+                return Ok(results);
+            }
+            let next = next.clone().ok_or(Error::NoPayload)?;
+            response = self.run_query(next)?;
+        }
+    }
+    /// Fetches only the keys of the entities matching `query`, without
+    /// deserializing them, following cursors until all keys are fetched.
+    pub fn query_keys<T: EntityKey>(&self, query: &Query<T>) -> Result<Vec<KeyPath>, Error> {
+        let mut query = query.clone().keys_only();
+        let mut keys = Vec::new();
+        loop {
+            let page = self.query_entity_page(&query)?;
+            let is_last = query.advance(&page);
+            for entity in page.results {
+                let key = entity.key
+                    .and_then(key::from_datastore_key)
+                    .ok_or(Error::NoPayload)?;
+                keys.push(key);
+            }
+            if is_last {
+                return Ok(keys);
+            }
+        }
+    }
+    /// The keys of all entities of kind `T`.
+    pub fn list_keys<T: EntityKey>(&self) -> Result<Vec<KeyPath>, Error> {
+        self.query_keys(&Query::<T>::new())
+    }
+    pub(crate) fn query_entity_page<T: EntityKey>(
+        &self,
+        query: &Query<T>,
+    ) -> Result<Page<google_datastore1::Entity>, Error> {
+        let batch = self.run_query(query.to_datastore_query(self.partition_id().as_ref())?)?
+            .batch
+            .ok_or(Error::NoPayload)?;
+        let results = batch.entity_results
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|x| x.entity)
+            .collect::<Vec<_>>();
+        Ok(Page::new(results, &batch))
+    }
+    /// Runs several aggregations over the entities matching `query` in one
+    /// request, returning their values in the same order.
+    pub fn aggregate<T: EntityKey>(
+        &self,
+        query: &Query<T>,
+        aggregations: &[Aggregation],
+    ) -> Result<Vec<AggregateValue>, Error> {
+        aggregation::run_aggregation_query(
+            &self.http,
+            &self.auth,
+            &self.project_id,
+            self.partition_id(),
+            self.read_options(),
+            query.to_datastore_query(self.partition_id().as_ref())?,
+            aggregations,
+        )
+    }
+    /// Number of entities matching `query`, without fetching them.
+    pub fn count<T: EntityKey>(&self, query: &Query<T>) -> Result<i64, Error> {
+        self.aggregate_one(query, Aggregation::Count)
+            .and_then(|x| x.as_i64().ok_or(Error::NoPayload))
+    }
+    /// Like `count`, but stops counting at `up_to`.
+    pub fn count_up_to<T: EntityKey>(&self, query: &Query<T>, up_to: i64) -> Result<i64, Error> {
+        self.aggregate_one(query, Aggregation::CountUpTo(up_to))
+            .and_then(|x| x.as_i64().ok_or(Error::NoPayload))
+    }
+    /// Sum of a numeric property over the entities matching `query`.
+    pub fn sum<T: EntityKey, P: ToString>(&self, query: &Query<T>, property: P) -> Result<AggregateValue, Error> {
+        self.aggregate_one(query, Aggregation::Sum(property.to_string()))
+    }
+    /// Average of a numeric property over the entities matching `query`;
+    /// `None` if no entity matches.
+    pub fn avg<T: EntityKey, P: ToString>(&self, query: &Query<T>, property: P) -> Result<Option<f64>, Error> {
+        self.aggregate_one(query, Aggregation::Avg(property.to_string()))
+            .map(|x| x.as_f64())
+    }
+    /// Starts a batch of writes, see `WriteBatch`.
+    pub fn write_batch(&self) -> WriteBatch {
+        WriteBatch::new(self.clone())
+    }
+    /// Inserts all `values`, committing at most `MAX_MUTATIONS_PER_COMMIT`
+    /// at a time. Fails before writing anything if a value cannot be
+    /// serialized; otherwise reports the outcome of each chunk.
+    pub fn insert_many<T, I>(&self, values: I) -> Result<Vec<ChunkResult>, Error>
+    where
+        T: Serialize + EntityKey,
+        I: IntoIterator<Item = T>,
+    {
+        let mut batch = self.write_batch();
+        for value in values {
+            batch.insert(value)?;
+        }
+        Ok(batch.commit())
+    }
+    /// Like `insert_many`, upserting.
+    pub fn upsert_many<T, I>(&self, values: I) -> Result<Vec<ChunkResult>, Error>
+    where
+        T: Serialize + EntityKey,
+        I: IntoIterator<Item = T>,
+    {
+        let mut batch = self.write_batch();
+        for value in values {
+            batch.upsert(value)?;
+        }
+        Ok(batch.commit())
+    }
+    /// Like `insert_many`, updating existing entities.
+    pub fn update_many<T, I>(&self, values: I) -> Result<Vec<ChunkResult>, Error>
+    where
+        T: Serialize + EntityKey,
+        I: IntoIterator<Item = T>,
+    {
+        let mut batch = self.write_batch();
+        for value in values {
+            batch.update(value)?;
+        }
+        Ok(batch.commit())
+    }
+    /// Deletes the root entities of kind `T` with the given keys, committing
+    /// at most `MAX_MUTATIONS_PER_COMMIT` at a time.
+    pub fn delete_many<T, K, I>(&self, keys: I) -> Vec<ChunkResult>
+    where
+        T: EntityKey,
+        K: Into<KeyId>,
+        I: IntoIterator<Item = K>,
+    {
+        let mut batch = self.write_batch();
+        for key in keys {
+            batch.delete::<T, K>(key);
+        }
+        batch.commit()
+    }
+    /// Starts a read-write transaction. See `Transaction` for details, and
+    /// `run_in_transaction` for automatic retries.
+    pub fn begin_transaction(&self) -> Result<Transaction, Error> {
+        self.begin_transaction_after(None)
+    }
+    /// Starts a read-only transaction: all reads made through it see the
+    /// same consistent snapshot, e.g. for reports spanning several kinds.
+    pub fn begin_read_only_transaction(&self) -> Result<ReadOnlyTransaction, Error> {
+        let client = self.begin_transaction_with(google_datastore1::TransactionOptions {
+            read_write: None,
+            read_only: Some(google_datastore1::ReadOnly::default()),
+        })?;
+        Ok(ReadOnlyTransaction::new(client))
+    }
+    /// Runs `f` in a read-only transaction, releasing it afterwards.
+    pub fn run_read_only<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&ReadOnlyTransaction) -> Result<R, Error>,
+    {
+        let tx = self.begin_read_only_transaction()?;
+        let result = f(&tx);
+        let _ = tx.close();
+        result
+    }
+    /// Runs `f` in a read-write transaction and commits it, retrying the
+    /// whole closure with exponential backoff when the commit (or any read)
+    /// fails due to contention with another transaction.
+    ///
+    /// Errors returned by `f` roll the transaction back and are returned
+    /// as-is, without retrying.
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # pub struct Counter { pub name: String, pub count: i64 }
+    /// # impl ddb::EntityKey for Counter {
+    /// #     fn entity_kind_key() -> String { String::from("Counter") }
+    /// #     fn entity_name_key(&self) -> String { self.name.clone() }
+    /// # }
+    /// let db = ddb::DatastoreClient::new().unwrap();
+    /// db.run_in_transaction(|tx| {
+    ///     let mut counter = tx.get::<Counter, _>("visits")?;
+    ///     counter.count += 1;
+    ///     tx.update(counter)
+    /// }).unwrap();
+    /// ```
+    pub fn run_in_transaction<F, R>(&self, mut f: F) -> Result<R, Error>
+    where
+        F: FnMut(&Transaction) -> Result<R, Error>,
+    {
+        let mut previous_transaction = None;
+        let mut attempt = 0;
+        loop {
+            let tx = self.begin_transaction_after(previous_transaction.take())?;
+            let tx_id = tx.id().to_owned();
+            let result = f(&tx).and_then(|x| tx.commit().map(|_| x));
+            match result {
+                Err(ref e) if transaction::is_contention(e) && attempt + 1 < transaction::MAX_ATTEMPTS => {
+                    let _ = self.rollback_transaction(&tx_id);
+                    transaction::backoff(attempt);
+                    previous_transaction = Some(tx_id);
+                    attempt += 1;
                 }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+                Err(e) => {
+                    let _ = self.rollback_transaction(&tx_id);
+                    return Err(e);
+                }
+                Ok(x) => return Ok(x),
+            }
+        }
+    }
+    /// Allocates `count` numeric ids for root entities of kind `T`, for
+    /// when ids are needed before the entities are written.
+    pub fn allocate_ids<T: EntityKey>(&self, count: usize) -> Result<Vec<i64>, Error> {
+        self.allocate_ids_with_ancestors::<T>(&[], count)
+    }
+    /// Like `allocate_ids`, for entities stored under the given ancestor path.
+    pub fn allocate_ids_with_ancestors<T: EntityKey>(
+        &self,
+        ancestors: &[PathElement],
+        count: usize,
+    ) -> Result<Vec<i64>, Error> {
+        let path = key::to_datastore_path(ancestors.to_vec(), T::entity_kind_key(), None);
+        let req = google_datastore1::AllocateIdsRequest {
+            keys: Some(vec![self.to_key(path); count]),
         };
         let result = self.handle
             .projects()
-            .commit(req, &self.project_id)
+            .allocate_ids(req, &self.project_id)
             .doit();
         match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+            Ok((_, response)) => {
+                response.keys
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| {
+                        match key::from_datastore_key(x).map(|x| x.id) {
+                            Some(KeyId::Id(id)) => Ok(id),
+                            _ => Err(Error::NoPayload),
+                        }
+                    })
+                    .collect()
+            }
+            Err(e) => Err(Error::from(e)),
         }
     }
-    pub fn upsert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
-            })
-            .and_then(|x| x.properties)
-            .ok_or(Error::Serialization {
-                msg: String::from("expecting struct/map like input")
-            })?;
-        let entity = google_datastore1::Entity {
-            properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
-                    }
-                ]),
-                partition_id: None
+    /// Prevents Datastore from allocating the given ids for root entities of
+    /// kind `T`, e.g. after importing entities with externally chosen ids.
+    pub fn reserve_ids<T: EntityKey>(&self, ids: &[i64]) -> Result<(), Error> {
+        self.reserve_ids_with_ancestors::<T>(&[], ids)
+    }
+    /// Like `reserve_ids`, for entities stored under the given ancestor path.
+    pub fn reserve_ids_with_ancestors<T: EntityKey>(
+        &self,
+        ancestors: &[PathElement],
+        ids: &[i64],
+    ) -> Result<(), Error> {
+        let keys = ids
+            .iter()
+            .map(|id| {
+                let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), *id);
+                self.to_key(key.into_datastore_path())
             })
+            .collect::<Vec<_>>();
+        let req = google_datastore1::ReserveIdsRequest {
+            keys: Some(keys),
+            database_id: None,
         };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: None,
-                    update: None,
-                    base_version: None,
-                    upsert: Some(entity),
-                }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+        let result = self.handle
+            .projects()
+            .reserve_ids(req, &self.project_id)
+            .doit();
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+    pub fn delete<T: EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<CommitResult, Error> {
+        self.delete_with_ancestors::<T, K>(&[], key)
+    }
+    /// Like `delete`, for entities stored under the given ancestor path
+    /// (outermost ancestor first).
+    pub fn delete_with_ancestors<T: EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<CommitResult, Error> {
+        let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key);
+        let key = self.to_key(key.into_datastore_path());
+        self.commit(batch::delete_mutation(key))
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CLIENT INTERNALS
+///////////////////////////////////////////////////////////////////////////////
+
+impl DatastoreClient {
+    fn begin_transaction_after(&self, previous_transaction: Option<String>) -> Result<Transaction, Error> {
+        let client = self.begin_transaction_with(google_datastore1::TransactionOptions {
+            read_write: Some(google_datastore1::ReadWrite {
+                previous_transaction,
+            }),
+            read_only: None,
+        })?;
+        Ok(Transaction::new(client))
+    }
+    /// Returns a copy of this client bound to the new transaction.
+    fn begin_transaction_with(&self, options: google_datastore1::TransactionOptions) -> Result<DatastoreClient, Error> {
+        let req = google_datastore1::BeginTransactionRequest {
+            transaction_options: Some(options),
         };
         let result = self.handle
             .projects()
-            .commit(req, &self.project_id)
+            .begin_transaction(req, &self.project_id)
+            .doit();
+        match result {
+            Ok((_, response)) => {
+                let id = response.transaction.ok_or(Error::NoPayload)?;
+                Ok(DatastoreClient {
+                    transaction: Some(id),
+                    ..self.clone()
+                })
+            }
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+    pub(crate) fn rollback_transaction(&self, transaction: &str) -> Result<(), Error> {
+        let req = google_datastore1::RollbackRequest {
+            transaction: Some(transaction.to_owned()),
+        };
+        let result = self.handle
+            .projects()
+            .rollback(req, &self.project_id)
             .doit();
         match result {
             Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+    pub(crate) fn transaction_id(&self) -> Option<&str> {
+        self.transaction.as_deref()
+    }
+    /// A transaction already implies strong consistency; Datastore rejects
+    /// setting both.
+    fn read_options(&self) -> Option<google_datastore1::ReadOptions> {
+        match (&self.transaction, self.read_consistency) {
+            (Some(transaction), _) => Some(google_datastore1::ReadOptions {
+                transaction: Some(transaction.clone()),
+                read_consistency: None,
+            }),
+            (None, Some(read_consistency)) => Some(google_datastore1::ReadOptions {
+                transaction: None,
+                read_consistency: Some(String::from(read_consistency.as_str())),
+            }),
+            (None, None) => None,
         }
     }
-    pub fn update<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = value.entity_name_key();
-        let properties = convert::to_datastore_value(value)
-            .and_then(|value| {
-                value.entity_value
+    fn partition_id(&self) -> Option<google_datastore1::PartitionId> {
+        self.namespace
+            .as_ref()
+            .map(|namespace| {
+                google_datastore1::PartitionId {
+                    project_id: Some(self.project_id.clone()),
+                    namespace_id: Some(namespace.clone()),
+                }
             })
+    }
+    pub(crate) fn to_key(&self, path: Vec<google_datastore1::PathElement>) -> google_datastore1::Key {
+        google_datastore1::Key {
+            path: Some(path),
+            partition_id: self.partition_id(),
+        }
+    }
+    pub(crate) fn to_entity<T: Serialize + EntityKey>(&self, value: T) -> Result<google_datastore1::Entity, Error> {
+        let path = key::to_datastore_path(
+            value.entity_ancestor_keys(),
+            T::entity_kind_key(),
+            value.entity_key_id(),
+        );
+        let key = self.to_key(path);
+        let mut value = convert::to_datastore_value(value)?;
+        if let Some(partition_id) = self.partition_id() {
+            convert::set_key_partitions(&mut value, &partition_id);
+        }
+        let properties = value.entity_value
             .and_then(|x| x.properties)
             .ok_or(Error::Serialization {
                 msg: String::from("expecting struct/map like input")
             })?;
-        let entity = google_datastore1::Entity {
+        Ok(google_datastore1::Entity {
             properties: Some(properties),
-            key: Some(google_datastore1::Key {
-                path: Some(vec![
-                    google_datastore1::PathElement {
-                        kind: Some(kind_key.to_owned()),
-                        name: Some(name_key.to_owned()),
-                        id: None
-                    }
-                ]),
-                partition_id: None
-            })
-        };
-        let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: None,
-                    update: Some(entity),
-                    base_version: None,
-                    upsert: None,
-                }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+            key: Some(key),
+        })
+    }
+    fn aggregate_one<T: EntityKey>(&self, query: &Query<T>, aggregation: Aggregation) -> Result<AggregateValue, Error> {
+        self.aggregate(query, &[aggregation])?
+            .pop()
+            .ok_or(Error::NoPayload)
+    }
+    fn try_get_by_path<T: DeserializeOwned>(&self, key: &KeyPath) -> Result<Option<T>, Error> {
+        let result = match self.lookup_one(key)? {
+            Some(result) => result,
+            None => return Ok(None),
         };
-        let result = self.handle
-            .projects()
-            .commit(req, &self.project_id)
-            .doit();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+        let payload = result.entity.ok_or(Error::NoPayload)?;
+        convert::from_datastore_entity(payload).map(Some)
+    }
+    /// `None` if Datastore reported the key as missing; fails if the
+    /// response mentions it neither as found, missing nor deferred.
+    fn lookup_one(&self, key: &KeyPath) -> Result<Option<google_datastore1::EntityResult>, Error> {
+        let key = self.to_key(key.clone().into_datastore_path());
+        // Like in `get_many_by_path`, request the key again while Datastore
+        // defers it.
+        loop {
+            let response = self.lookup(vec![key.clone()])?;
+            if let Some(found) = response.found.and_then(|xs| xs.into_iter().next()) {
+                return Ok(Some(found));
+            }
+            match (response.missing, response.deferred) {
+                (Some(ref missing), _) if !missing.is_empty() => return Ok(None),
+                (_, Some(ref deferred)) if !deferred.is_empty() => continue,
+                _ => return Err(Error::NoPayload),
+            }
         }
     }
-    pub fn get<T: DeserializeOwned + EntityKey, K: ToString>(&self, name_key: K) -> Result<T, Error> {
-        let kind_key = T::entity_kind_key();
+    fn lookup(&self, keys: Vec<google_datastore1::Key>) -> Result<google_datastore1::LookupResponse, Error> {
         let req = google_datastore1::LookupRequest {
-            keys: Some(vec![
-                google_datastore1::Key {
-                    path: Some(vec![
-                        google_datastore1::PathElement {
-                            kind: Some(kind_key),
-                            name: Some(name_key.to_string()),
-                            id: None
-                        }
-                    ]),
-                    partition_id: None
-                }]),
-            read_options: None
+            keys: Some(keys),
+            read_options: self.read_options(),
         };
         let result = self.handle
             .projects()
             .lookup(req, &self.project_id)
             .doit();
         match result {
-            Ok((_, lookup_response)) => {
-                let payload = lookup_response.found
-                    .and_then(|entities| {
-                        entities.first().map(|x| x.clone())
-                    })
-                    .and_then(|x| x.entity)
-                    .ok_or(Error::NoPayload)?;
-                convert::from_datastore_entity(payload.clone())
-                    .ok_or_else(|| {
-                        Error::Deserialization {
-                            msg: String::from("conversion or parser error")
-                        }
-                    })
-            }
-            Err(e) => Err(Error::DatabaseResponse(e)),
+            Ok((_, response)) => Ok(response),
+            Err(e) => Err(Error::from(e)),
         }
     }
-    pub fn list<T: DeserializeOwned + EntityKey>(&self) -> Result<Vec<T>, Error> {
-        let kind_key = T::entity_kind_key();
-        let mut query = RunQueryRequest{
-            query: Some(google_datastore1::Query{
-                start_cursor: None,
-                kind: Some(vec![ google_datastore1::KindExpression { name: Some(kind_key)} ]),
-                projection: None,
-                distinct_on: None,
-                filter: None,
-                limit: None,
-                offset: None,
-                end_cursor:None,
-                order: None,
-            }),
-            partition_id: None,
+    fn run_query(&self, query: google_datastore1::Query) -> Result<google_datastore1::RunQueryResponse, Error> {
+        self.run_query_request(RunQueryRequest {
+            query: Some(query),
+            partition_id: self.partition_id(),
             gql_query: None,
-            read_options: None,
-        };
-
+            read_options: self.read_options(),
+        })
+    }
+    fn run_query_request(&self, req: RunQueryRequest) -> Result<google_datastore1::RunQueryResponse, Error> {
         let result = self.handle
             .projects()
-            //.lookup(req, &self.project_id)
-            .run_query(query, &self.project_id)
+            .run_query(req, &self.project_id)
             .doit();
-
         match result {
-            Ok((_, query_response)) => {
-                let payload = query_response.batch
-                    .and_then(|batch| batch.entity_results )
-                    .and_then(|entities| {
-                        Some(entities.into_iter().filter_map(|x| x.entity)
-                        .filter_map(|x| convert::from_datastore_entity(x.clone()))
-                        .collect::<Vec<T>>())
-                    })
-                    .ok_or(Error::NoPayload)?;
-                    Ok(payload)
-            }
-            Err(e) => Err(Error::DatabaseResponse(e)),
+            Ok((_, response)) => Ok(response),
+            Err(e) => Err(Error::from(e)),
         }
     }
-    pub fn delete<T: EntityKey, K: ToString>(&self, name_key: K) -> Result<(), Error> {
-        let kind_key = T::entity_kind_key();
-        let name_key = name_key.to_string();
-        let entity_key = google_datastore1::Key {
-            path: Some(vec![
-                google_datastore1::PathElement {
-                    kind: Some(kind_key.to_owned()),
-                    name: Some(name_key.to_owned()),
-                    id: None
-                }
-            ]),
-            partition_id: None
+    fn commit(&self, mutation: google_datastore1::Mutation) -> Result<CommitResult, Error> {
+        self.commit_mutations(vec![mutation])
+    }
+    /// Commits a mutation carrying a `base_version`; Datastore reports a
+    /// mismatch through the mutation result rather than as an error.
+    fn commit_versioned(&self, mutation: google_datastore1::Mutation) -> Result<CommitResult, Error> {
+        let key = mutation.delete
+            .clone()
+            .or_else(|| mutation.update.as_ref().and_then(|x| x.key.clone()))
+            .or_else(|| mutation.upsert.as_ref().and_then(|x| x.key.clone()))
+            .and_then(key::from_datastore_key);
+        let result = self.commit(mutation)?;
+        if result.mutation_results.iter().any(|x| x.conflict_detected) {
+            Err(Error::VersionConflict {key})
+        } else {
+            Ok(result)
+        }
+    }
+    /// Commits as part of this client's transaction, if it has one.
+    pub(crate) fn commit_mutations(
+        &self,
+        mutations: Vec<google_datastore1::Mutation>,
+    ) -> Result<CommitResult, Error> {
+        let mode = match self.transaction {
+            Some(_) => "TRANSACTIONAL",
+            None => "NON_TRANSACTIONAL",
         };
         let req = google_datastore1::CommitRequest {
-            transaction: None,
-            mutations: Some(vec![
-                google_datastore1::Mutation {
-                    insert: None,
-                    delete: Some(entity_key),
-                    update: None,
-                    base_version: None,
-                    upsert: None,
-                }
-            ]),
-            mode: Some(String::from("NON_TRANSACTIONAL"))
+            transaction: self.transaction.clone(),
+            mutations: Some(mutations),
+            mode: Some(String::from(mode))
         };
         let result = self.handle
             .projects()
             .commit(req, &self.project_id)
             .doit();
         match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::DatabaseResponse(e))
+            Ok((_, response)) => Ok(CommitResult::from_datastore(response)),
+            Err(e) => Err(Error::from(e))
         }
     }
 }
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::io::Read;
+use crate::key::KeyPath;
+
+///////////////////////////////////////////////////////////////////////////////
+// STATUS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The error status returned by Datastore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// HTTP status code, e.g. `409`.
+    pub code: u16,
+    /// Canonical status, e.g. `ABORTED`, when the response carried one.
+    pub canonical: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.canonical {
+            Some(canonical) => write!(f, "{} {}: {}", self.code, canonical, self.message),
+            None => write!(f, "{} {}", self.code, self.message),
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ERROR
+///////////////////////////////////////////////////////////////////////////////
+
+/// Details of `Error::InvalidEntity`, boxed to keep `Error` small.
+#[derive(Debug, Clone)]
+pub struct InvalidEntity {
+    /// Kind and key of the entity, when known.
+    pub key: Option<KeyPath>,
+    /// The offending property, e.g. `address.zip[2]`; empty for the
+    /// entity itself.
+    pub path: String,
+    /// What serde expected, e.g. `invalid type: string "abc", expected u32`.
+    pub msg: String,
+    /// What Datastore held at `path`, e.g. `string_value "abc"`.
+    pub found: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// A value could not be converted into a Datastore entity or value.
+    Serialization {
+        msg: String,
+    },
+    /// A Datastore entity or response could not be converted into the
+    /// requested type.
+    Deserialization {
+        msg: String,
+    },
+    /// A stored entity does not match the type it was read into.
+    InvalidEntity(Box<InvalidEntity>),
+    /// The response lacked data it was expected to contain.
+    NoPayload,
+    /// No entity exists at the requested key.
+    EntityNotFound {
+        key: KeyPath,
+    },
+    /// Datastore reported a resource, such as the database, as not found.
+    NotFound {
+        status: Status,
+    },
+    /// An insert collided with an existing entity.
+    AlreadyExists {
+        status: Status,
+    },
+    /// An update targeted an entity that does not exist.
+    MissingOnUpdate {
+        status: Status,
+    },
+    /// The transaction was aborted due to contention; retrying may succeed.
+    Aborted {
+        status: Status,
+    },
+    /// The stored entity no longer has the version a write expected.
+    VersionConflict {
+        key: Option<KeyPath>,
+    },
+    PermissionDenied {
+        status: Status,
+    },
+    QuotaExceeded {
+        status: Status,
+    },
+    /// The query requires a composite index that does not exist yet.
+    NeedsIndex {
+        status: Status,
+    },
+    InvalidArgument {
+        status: Status,
+    },
+    /// Any other error status returned by Datastore.
+    Server {
+        status: Status,
+    },
+    /// The request could not be sent or its response could not be read.
+    Transport {
+        msg: String,
+    },
+    /// Datastore rejected the credentials.
+    Unauthenticated {
+        status: Status,
+    },
+    /// No valid credentials could be obtained.
+    Auth {
+        msg: String,
+    },
+}
+
+impl Error {
+    /// Classifies an error status returned by Datastore.
+    ///
+    /// Several canonical statuses share an HTTP status code, e.g. `ABORTED`
+    /// and `ALREADY_EXISTS` are both `409`. The canonical status tells them
+    /// apart; responses parsed by `google_datastore1` drop it, so the message
+    /// is used as a fallback.
+    pub(crate) fn from_status(code: u16, canonical: Option<String>, message: String) -> Self {
+        let lowercase = message.to_lowercase();
+        let missing_index = lowercase.contains("no matching index");
+        let missing_entity = lowercase.contains("no entity to update");
+        let status = Status {code, canonical, message};
+        match status.canonical.as_deref() {
+            Some("INVALID_ARGUMENT") | Some("FAILED_PRECONDITION") if missing_index => Error::NeedsIndex {status},
+            Some("INVALID_ARGUMENT") => Error::InvalidArgument {status},
+            Some("UNAUTHENTICATED") => Error::Unauthenticated {status},
+            Some("PERMISSION_DENIED") => Error::PermissionDenied {status},
+            Some("NOT_FOUND") if missing_entity => Error::MissingOnUpdate {status},
+            Some("NOT_FOUND") => Error::NotFound {status},
+            Some("ALREADY_EXISTS") => Error::AlreadyExists {status},
+            Some("ABORTED") => Error::Aborted {status},
+            Some("RESOURCE_EXHAUSTED") => Error::QuotaExceeded {status},
+            Some(_) => Error::Server {status},
+            None => match code {
+                400 | 412 if missing_index => Error::NeedsIndex {status},
+                400 => Error::InvalidArgument {status},
+                401 => Error::Unauthenticated {status},
+                403 => Error::PermissionDenied {status},
+                404 if missing_entity => Error::MissingOnUpdate {status},
+                404 => Error::NotFound {status},
+                409 if lowercase.contains("already exists") => Error::AlreadyExists {status},
+                409 => Error::Aborted {status},
+                429 => Error::QuotaExceeded {status},
+                _ => Error::Server {status},
+            },
+        }
+    }
+    /// Classifies an error response body of the form
+    /// `{"error": {"code", "status", "message"}}`, falling back to `code`
+    /// and the raw `body` for whatever it lacks.
+    pub(crate) fn from_response_body(code: u16, body: &str) -> Self {
+        let json = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+        Error::from_error_json(code, &json, body)
+    }
+    fn from_error_json(code: u16, json: &serde_json::Value, fallback: &str) -> Self {
+        let error = &json["error"];
+        let code = error["code"].as_u64().map(|x| x as u16).unwrap_or(code);
+        let canonical = error["status"].as_str().map(|x| x.to_owned());
+        let message = error["message"].as_str().unwrap_or(fallback).to_owned();
+        Error::from_status(code, canonical, message)
+    }
+    /// The status returned by Datastore, for errors that originate from it.
+    pub fn status(&self) -> Option<&Status> {
+        match self {
+            Error::NotFound {status} |
+            Error::AlreadyExists {status} |
+            Error::MissingOnUpdate {status} |
+            Error::Aborted {status} |
+            Error::PermissionDenied {status} |
+            Error::QuotaExceeded {status} |
+            Error::NeedsIndex {status} |
+            Error::InvalidArgument {status} |
+            Error::Server {status} |
+            Error::Unauthenticated {status} => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serialization {msg} => write!(f, "serialization error: {}", msg),
+            Error::Deserialization {msg} => write!(f, "deserialization error: {}", msg),
+            Error::InvalidEntity(x) => {
+                match &x.key {
+                    Some(key) => write!(f, "cannot deserialize {}", key)?,
+                    None => write!(f, "cannot deserialize value")?,
+                }
+                if !x.path.is_empty() {
+                    write!(f, " at `{}`", x.path)?;
+                }
+                write!(f, ": {} (datastore held {})", x.msg, x.found)
+            }
+            Error::NoPayload => write!(f, "datastore response is missing the expected payload"),
+            Error::EntityNotFound {key} => write!(f, "no entity at {}", key),
+            Error::NotFound {status} => write!(f, "not found: {}", status),
+            Error::AlreadyExists {status} => write!(f, "entity already exists: {}", status),
+            Error::MissingOnUpdate {status} => write!(f, "no entity to update: {}", status),
+            Error::Aborted {status} => write!(f, "aborted: {}", status),
+            Error::VersionConflict {key: Some(key)} => write!(f, "version conflict for {}", key),
+            Error::VersionConflict {key: None} => write!(f, "version conflict"),
+            Error::PermissionDenied {status} => write!(f, "permission denied: {}", status),
+            Error::QuotaExceeded {status} => write!(f, "quota exceeded: {}", status),
+            Error::NeedsIndex {status} => write!(f, "missing index: {}", status),
+            Error::InvalidArgument {status} => write!(f, "invalid argument: {}", status),
+            Error::Server {status} => write!(f, "datastore error: {}", status),
+            Error::Transport {msg} => write!(f, "transport error: {}", msg),
+            Error::Unauthenticated {status} => write!(f, "unauthenticated: {}", status),
+            Error::Auth {msg} => write!(f, "auth error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Serialization {msg: msg.to_string()}
+    }
+}
+
+impl From<google_datastore1::Error> for Error {
+    fn from(e: google_datastore1::Error) -> Self {
+        match e {
+            google_datastore1::Error::HttpError(e) => Error::Transport {msg: e.to_string()},
+            google_datastore1::Error::BadRequest(response) => {
+                // Read through serde, as the fields of `ErrorResponse` are
+                // not public.
+                let response = serde_json::to_value(&response).unwrap_or_default();
+                Error::from_error_json(400, &response, "")
+            }
+            google_datastore1::Error::Failure(mut response) => {
+                let code = response.status.to_u16();
+                let mut body = String::new();
+                let _ = response.read_to_string(&mut body);
+                if body.trim().is_empty() {
+                    body = response.status.canonical_reason().unwrap_or_default().to_owned();
+                }
+                Error::from_response_body(code, &body)
+            }
+            google_datastore1::Error::MissingAPIKey => Error::Auth {
+                msg: String::from("missing API key"),
+            },
+            google_datastore1::Error::MissingToken(e) => Error::Auth {msg: e.to_string()},
+            google_datastore1::Error::JsonDecodeError(_, e) => Error::Deserialization {msg: e.to_string()},
+            e => Error::Transport {msg: e.to_string()},
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(code: u16, canonical: Option<&str>, message: &str) -> Error {
+        Error::from_status(code, canonical.map(|x| x.to_owned()), message.to_owned())
+    }
+
+    #[test]
+    fn canonical_status_takes_precedence() {
+        assert!(matches!(classify(409, Some("ABORTED"), "entity already exists"), Error::Aborted {..}));
+        assert!(matches!(classify(409, Some("ALREADY_EXISTS"), "conflict"), Error::AlreadyExists {..}));
+        assert!(matches!(classify(404, Some("NOT_FOUND"), "no entity to update: app: \"x\""), Error::MissingOnUpdate {..}));
+        assert!(matches!(classify(404, Some("NOT_FOUND"), "database not found"), Error::NotFound {..}));
+        assert!(matches!(classify(412, Some("FAILED_PRECONDITION"), "no matching index found."), Error::NeedsIndex {..}));
+        assert!(matches!(classify(400, Some("INVALID_ARGUMENT"), "bad key"), Error::InvalidArgument {..}));
+        assert!(matches!(classify(403, Some("PERMISSION_DENIED"), ""), Error::PermissionDenied {..}));
+        assert!(matches!(classify(429, Some("RESOURCE_EXHAUSTED"), ""), Error::QuotaExceeded {..}));
+        assert!(matches!(classify(503, Some("UNAVAILABLE"), ""), Error::Server {..}));
+    }
+
+    #[test]
+    fn message_is_the_fallback() {
+        assert!(matches!(classify(409, None, "Entity already exists"), Error::AlreadyExists {..}));
+        assert!(matches!(classify(409, None, "too much contention"), Error::Aborted {..}));
+        assert!(matches!(classify(404, None, "No entity to update"), Error::MissingOnUpdate {..}));
+        assert!(matches!(classify(400, None, "no matching index found"), Error::NeedsIndex {..}));
+        assert!(matches!(classify(400, None, "bad key"), Error::InvalidArgument {..}));
+        assert!(matches!(classify(500, None, "internal"), Error::Server {..}));
+    }
+
+    #[test]
+    fn unauthenticated_keeps_its_status() {
+        let error = classify(401, None, "invalid credentials");
+        assert!(matches!(error, Error::Unauthenticated {..}));
+        assert_eq!(error.status().map(|x| x.code), Some(401));
+        assert_eq!(error.status().map(|x| x.message.as_str()), Some("invalid credentials"));
+    }
+
+    #[test]
+    fn keys_are_displayed_as_paths() {
+        let key = KeyPath::new(vec![crate::key::PathElement::new("Org", "acme")], "Task", 42);
+        assert_eq!(Error::EntityNotFound {key: key.clone()}.to_string(), "no entity at Org:acme/Task:42");
+        assert_eq!(Error::VersionConflict {key: Some(key)}.to_string(), "version conflict for Org:acme/Task:42");
+    }
+
+    #[test]
+    fn response_body_is_parsed() {
+        let body = r#"{"error": {"code": 409, "status": "ABORTED", "message": "too much contention"}}"#;
+        let error = Error::from_response_body(409, body);
+        assert!(matches!(error, Error::Aborted {..}));
+        assert_eq!(error.status(), Some(&Status {
+            code: 409,
+            canonical: Some(String::from("ABORTED")),
+            message: String::from("too much contention"),
+        }));
+        let error = Error::from_response_body(502, "Bad Gateway");
+        assert_eq!(error.status().map(|x| x.message.as_str()), Some("Bad Gateway"));
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Serialize, Serializer, Deserialize};
+use crate::convert;
+
+///////////////////////////////////////////////////////////////////////////////
+// GEO POINT
+///////////////////////////////////////////////////////////////////////////////
+
+/// A location, stored as a Datastore `geo_point_value`.
+///
+/// Other serializers see a struct with `latitude` and `longitude`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub struct GeoPoint {
+    /// In degrees, within `[-90, 90]`.
+    pub latitude: f64,
+    /// In degrees, within `[-180, 180]`.
+    pub longitude: f64,
+}
+
+impl GeoPoint {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        GeoPoint {latitude, longitude}
+    }
+}
+
+impl Serialize for GeoPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct LatLng {
+            latitude: f64,
+            longitude: f64,
+        }
+        let value = LatLng {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        };
+        serializer.serialize_newtype_struct(convert::GEO_POINT, &value)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// GEO-TYPES
+///////////////////////////////////////////////////////////////////////////////
+
+/// `x` is the longitude and `y` the latitude.
+#[cfg(feature = "geo-types")]
+impl From<geo_types::Point<f64>> for GeoPoint {
+    fn from(point: geo_types::Point<f64>) -> Self {
+        GeoPoint::new(point.y(), point.x())
+    }
+}
+
+#[cfg(feature = "geo-types")]
+impl From<GeoPoint> for geo_types::Point<f64> {
+    fn from(point: GeoPoint) -> Self {
+        geo_types::Point::new(point.longitude, point.latitude)
+    }
+}
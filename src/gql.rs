@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use crate::convert;
+use crate::db::Error;
+
+///////////////////////////////////////////////////////////////////////////////
+// GQL QUERY
+///////////////////////////////////////////////////////////////////////////////
+
+/// A GQL query string with its bindings.
+///
+/// Named bindings are referenced as `@name` and positional bindings as
+/// `@1`, `@2`, ... in the query string.
+///
+/// ```no_run
+/// use ddb::GqlQuery;
+/// let db = ddb::DatastoreClient::new().unwrap();
+/// let query = GqlQuery::new("SELECT * FROM TodoItem WHERE title = @title LIMIT @1")
+///     .bind("title", "lorem ipsum")
+///     .bind_positional(10);
+/// // Any `DeserializeOwned` type works, including dynamic ones.
+/// let items: Vec<serde_json::Value> = db.gql_query(&query).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct GqlQuery {
+    query_string: String,
+    allow_literals: bool,
+    /// Errors are those of values that could not be serialized.
+    named_bindings: HashMap<String, Result<google_datastore1::Value, Error>>,
+    positional_bindings: Vec<Result<google_datastore1::Value, Error>>,
+}
+
+impl GqlQuery {
+    pub fn new<Q: ToString>(query_string: Q) -> Self {
+        GqlQuery {
+            query_string: query_string.to_string(),
+            allow_literals: true,
+            named_bindings: HashMap::new(),
+            positional_bindings: Vec::new(),
+        }
+    }
+    /// Binds `@name` to `value`.
+    pub fn bind<N: ToString, V: Serialize>(mut self, name: N, value: V) -> Self {
+        self.named_bindings.insert(name.to_string(), convert::to_datastore_value(value));
+        self
+    }
+    /// Binds the next positional parameter (`@1`, then `@2`, ...) to `value`.
+    pub fn bind_positional<V: Serialize>(mut self, value: V) -> Self {
+        self.positional_bindings.push(convert::to_datastore_value(value));
+        self
+    }
+    /// Whether literal values may appear in the query string. Defaults to
+    /// `true`; disable it to enforce that all values go through bindings.
+    pub fn allow_literals(mut self, allow_literals: bool) -> Self {
+        self.allow_literals = allow_literals;
+        self
+    }
+    pub(crate) fn to_datastore_gql(&self) -> Result<google_datastore1::GqlQuery, Error> {
+        let to_parameter = |value: &Result<google_datastore1::Value, Error>| {
+            value
+                .clone()
+                .map(|value| {
+                    google_datastore1::GqlQueryParameter {
+                        cursor: None,
+                        value: Some(value),
+                    }
+                })
+        };
+        let named_bindings = self.named_bindings
+            .iter()
+            .map(|(name, value)| to_parameter(value).map(|x| (name.clone(), x)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let positional_bindings = self.positional_bindings
+            .iter()
+            .map(to_parameter)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(google_datastore1::GqlQuery {
+            query_string: Some(self.query_string.clone()),
+            allow_literals: Some(self.allow_literals),
+            named_bindings: Some(named_bindings),
+            positional_bindings: Some(positional_bindings),
+        })
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings() {
+        let query = GqlQuery::new("SELECT * FROM Task WHERE title = @title AND rank > @1 LIMIT @2")
+            .bind("title", "lorem ipsum")
+            .bind_positional(3)
+            .bind_positional(10);
+        let query = query.to_datastore_gql().unwrap();
+        assert_eq!(
+            query.query_string.as_deref(),
+            Some("SELECT * FROM Task WHERE title = @title AND rank > @1 LIMIT @2"),
+        );
+        let named = query.named_bindings.unwrap();
+        assert_eq!(named.len(), 1);
+        let title = named["title"].value.as_ref().and_then(|x| x.string_value.as_deref());
+        assert_eq!(title, Some("lorem ipsum"));
+        let positional = query.positional_bindings
+            .unwrap()
+            .into_iter()
+            .map(|x| x.value.and_then(|x| x.integer_value).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(positional, vec!["3", "10"]);
+    }
+
+    #[test]
+    fn allow_literals() {
+        let query = GqlQuery::new("SELECT * FROM Task");
+        assert_eq!(query.to_datastore_gql().unwrap().allow_literals, Some(true));
+        let query = query.allow_literals(false);
+        assert_eq!(query.to_datastore_gql().unwrap().allow_literals, Some(false));
+    }
+
+    #[test]
+    fn unserializable_bindings_fail() {
+        let mut map = HashMap::new();
+        map.insert(vec![1], 1);
+        let query = GqlQuery::new("SELECT * FROM Task WHERE tags = @tags").bind("tags", &map);
+        assert!(matches!(query.to_datastore_gql(), Err(Error::Serialization {..})));
+        let query = GqlQuery::new("SELECT * FROM Task WHERE tags = @1").bind_positional(map);
+        assert!(matches!(query.to_datastore_gql(), Err(Error::Serialization {..})));
+    }
+}
@@ -0,0 +1,355 @@
+use std::marker::PhantomData;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use crate::convert;
+use crate::db::EntityKey;
+
+///////////////////////////////////////////////////////////////////////////////
+// KEY IDENTIFIERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The identifier part of a key: either a string name or a numeric id.
+///
+/// Entities written by this library default to name keys, while other
+/// Datastore clients commonly use numeric ids.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyId {
+    Name(String),
+    Id(i64),
+}
+
+impl std::fmt::Display for KeyId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyId::Name(x) => write!(f, "{}", x),
+            KeyId::Id(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl From<String> for KeyId {
+    fn from(x: String) -> Self {
+        KeyId::Name(x)
+    }
+}
+
+impl From<&String> for KeyId {
+    fn from(x: &String) -> Self {
+        KeyId::Name(x.clone())
+    }
+}
+
+impl From<&str> for KeyId {
+    fn from(x: &str) -> Self {
+        KeyId::Name(x.to_owned())
+    }
+}
+
+impl From<i64> for KeyId {
+    fn from(x: i64) -> Self {
+        KeyId::Id(x)
+    }
+}
+
+/// Lets unsuffixed literals such as `PathElement::new("Task", 42)` pick an
+/// id, as they default to `i32`.
+impl From<i32> for KeyId {
+    fn from(x: i32) -> Self {
+        KeyId::Id(i64::from(x))
+    }
+}
+
+impl From<u32> for KeyId {
+    fn from(x: u32) -> Self {
+        KeyId::Id(i64::from(x))
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// KEY PATHS
+///////////////////////////////////////////////////////////////////////////////
+
+/// One `(kind, id)` step of an entity key path.
+///
+/// A list of these, outermost first, describes the ancestors of an entity,
+/// e.g. `[Org, Project]` for a `Task` stored in that project's entity group.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PathElement {
+    pub kind: String,
+    pub id: KeyId,
+}
+
+impl PathElement {
+    pub fn new<K: ToString, I: Into<KeyId>>(kind: K, id: I) -> Self {
+        PathElement {
+            kind: kind.to_string(),
+            id: id.into(),
+        }
+    }
+    /// The last element of the key path of `value`, or `None` if its key is
+    /// incomplete.
+    pub fn of<T: EntityKey + ?Sized>(value: &T) -> Option<Self> {
+        value
+            .entity_key_id()
+            .map(|id| PathElement::new(T::entity_kind_key(), id))
+    }
+}
+
+/// Formats as `kind:id`, e.g. `Org:acme`.
+impl std::fmt::Display for PathElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.kind, self.id)
+    }
+}
+
+/// The complete key of an entity: its ancestors, kind and identifier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyPath {
+    /// Outermost ancestor first.
+    pub ancestors: Vec<PathElement>,
+    pub kind: String,
+    pub id: KeyId,
+}
+
+impl KeyPath {
+    pub fn new<K: ToString, I: Into<KeyId>>(ancestors: Vec<PathElement>, kind: K, id: I) -> Self {
+        KeyPath {
+            ancestors,
+            kind: kind.to_string(),
+            id: id.into(),
+        }
+    }
+    /// The key path of `value`, or `None` if its key is incomplete.
+    pub fn of<T: EntityKey + ?Sized>(value: &T) -> Option<Self> {
+        value
+            .entity_key_id()
+            .map(|id| KeyPath::new(value.entity_ancestor_keys(), T::entity_kind_key(), id))
+    }
+    /// The ancestors followed by this key's own element.
+    pub fn path(&self) -> Vec<PathElement> {
+        let mut path = self.ancestors.clone();
+        path.push(PathElement::new(&self.kind, self.id.clone()));
+        path
+    }
+    pub(crate) fn into_datastore_path(self) -> Vec<google_datastore1::PathElement> {
+        to_datastore_path(self.ancestors, self.kind, Some(self.id))
+    }
+}
+
+/// Formats the path outermost first, e.g. `Org:acme/Task:42`.
+impl std::fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for ancestor in &self.ancestors {
+            write!(f, "{}/", ancestor)?;
+        }
+        write!(f, "{}:{}", self.kind, self.id)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TYPED KEYS
+///////////////////////////////////////////////////////////////////////////////
+
+/// A reference to an entity of type `T`, stored as a Datastore `key_value`.
+///
+/// Meant for fields, e.g. `owner: Key<User>`; resolve it with
+/// `DatastoreClient::get_by_key`. Deserializing fails for keys of another
+/// kind. Other serializers see a list of `{kind, name}` or `{kind, id}`
+/// elements, outermost first.
+pub struct Key<T> {
+    path: KeyPath,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: EntityKey> Key<T> {
+    /// The key of a root entity of kind `T`.
+    pub fn new<I: Into<KeyId>>(id: I) -> Self {
+        Key::with_ancestors(Vec::new(), id)
+    }
+    pub fn with_ancestors<I: Into<KeyId>>(ancestors: Vec<PathElement>, id: I) -> Self {
+        Key {
+            path: KeyPath::new(ancestors, T::entity_kind_key(), id),
+            marker: PhantomData,
+        }
+    }
+    /// The key of `value`, or `None` if its key is incomplete.
+    pub fn of(value: &T) -> Option<Self> {
+        KeyPath::of(value).map(|path| Key {path, marker: PhantomData})
+    }
+    /// `None` if `path` is not of kind `T`.
+    pub fn from_path(path: KeyPath) -> Option<Self> {
+        if path.kind == T::entity_kind_key() {
+            Some(Key {path, marker: PhantomData})
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Key<T> {
+    pub fn path(&self) -> &KeyPath {
+        &self.path
+    }
+    pub fn into_path(self) -> KeyPath {
+        self.path
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        Key {
+            path: self.path.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Key<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Key").field(&self.path).finish()
+    }
+}
+
+impl<T> PartialEq for Key<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl<T> Eq for Key<T> {}
+
+impl<T> std::hash::Hash for Key<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state)
+    }
+}
+
+impl<T> From<Key<T>> for KeyPath {
+    fn from(key: Key<T>) -> Self {
+        key.path
+    }
+}
+
+impl<T> Serialize for Key<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(convert::KEY, &to_serde_path(&self.path))
+    }
+}
+
+impl<'de, T: EntityKey> Deserialize<'de> for Key<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = Vec::<SerdePathElement>::deserialize(deserializer)?;
+        let path = from_serde_path(path)
+            .ok_or_else(|| de::Error::custom("expected a non-empty key path"))?;
+        let kind = T::entity_kind_key();
+        if path.kind != kind {
+            return Err(de::Error::custom(format!(
+                "expected a key of kind `{}`, found `{}`",
+                kind,
+                path.kind,
+            )));
+        }
+        Ok(Key {path, marker: PhantomData})
+    }
+}
+
+/// How key path elements appear to serde.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SerdePathElement {
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+}
+
+pub(crate) fn to_serde_path(key: &KeyPath) -> Vec<SerdePathElement> {
+    key.path()
+        .into_iter()
+        .map(|x| {
+            let (name, id) = match x.id {
+                KeyId::Name(name) => (Some(name), None),
+                KeyId::Id(id) => (None, Some(id)),
+            };
+            SerdePathElement {kind: x.kind, name, id}
+        })
+        .collect()
+}
+
+/// Returns `None` for empty paths or elements without a name or id.
+fn from_serde_path(path: Vec<SerdePathElement>) -> Option<KeyPath> {
+    let mut path = path
+        .into_iter()
+        .map(|x| {
+            let id = match (x.name, x.id) {
+                (Some(name), _) => KeyId::Name(name),
+                (None, Some(id)) => KeyId::Id(id),
+                (None, None) => return None,
+            };
+            Some(PathElement {kind: x.kind, id})
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let last = path.pop()?;
+    Some(KeyPath {
+        ancestors: path,
+        kind: last.kind,
+        id: last.id,
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// DATASTORE CONVERSION
+///////////////////////////////////////////////////////////////////////////////
+
+/// A `None` id leaves the last element incomplete, for Datastore to allocate.
+pub(crate) fn to_datastore_path(
+    ancestors: Vec<PathElement>,
+    kind: String,
+    id: Option<KeyId>,
+) -> Vec<google_datastore1::PathElement> {
+    let to_element = |kind: String, id: Option<KeyId>| {
+        let (name, id) = match id {
+            Some(KeyId::Name(name)) => (Some(name), None),
+            Some(KeyId::Id(id)) => (None, Some(id.to_string())),
+            None => (None, None),
+        };
+        google_datastore1::PathElement {
+            kind: Some(kind),
+            name,
+            id,
+        }
+    };
+    ancestors
+        .into_iter()
+        .map(|x| to_element(x.kind, Some(x.id)))
+        .chain(std::iter::once(to_element(kind, id)))
+        .collect()
+}
+
+fn from_datastore_path_element(x: google_datastore1::PathElement) -> Option<PathElement> {
+    let id = match (x.name, x.id) {
+        (Some(name), _) => KeyId::Name(name),
+        (None, Some(id)) => KeyId::Id(id.parse().ok()?),
+        (None, None) => return None,
+    };
+    Some(PathElement {
+        kind: x.kind?,
+        id,
+    })
+}
+
+/// Returns `None` for incomplete or malformed keys.
+pub(crate) fn from_datastore_key(key: google_datastore1::Key) -> Option<KeyPath> {
+    let mut path = key.path?
+        .into_iter()
+        .map(from_datastore_path_element)
+        .collect::<Option<Vec<_>>>()?;
+    let last = path.pop()?;
+    Some(KeyPath {
+        ancestors: path,
+        kind: last.kind,
+        id: last.id,
+    })
+}
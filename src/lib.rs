@@ -27,9 +27,18 @@
 //! db.upsert(item);
 //! ```
 
+mod aggregation;
+mod batch;
 mod convert;
 mod db;
+mod error;
 mod auth;
+mod geo;
+mod gql;
+mod key;
+mod query;
+pub mod timestamp;
+mod transaction;
 
 pub use db::*;
 
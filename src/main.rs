@@ -1,8 +1,17 @@
 #![allow(unused)]
 
+mod aggregation;
+mod batch;
 mod convert;
 mod db;
+mod error;
 mod auth;
+mod geo;
+mod gql;
+mod key;
+mod query;
+mod timestamp;
+mod transaction;
 
 use serde::{Serialize, Deserialize};
 pub use db::*;
@@ -0,0 +1,781 @@
+use std::marker::PhantomData;
+use serde::Serialize;
+use crate::convert;
+use crate::db::{DatastoreClient, EntityKey, Error, KeyPath};
+
+///////////////////////////////////////////////////////////////////////////////
+// FILTERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Comparison operator of a property filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyOp {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    /// The property equals one of the values of an array.
+    In,
+    /// The property equals none of the values of an array.
+    NotIn,
+}
+
+impl PropertyOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PropertyOp::Equal => "EQUAL",
+            PropertyOp::NotEqual => "NOT_EQUAL",
+            PropertyOp::LessThan => "LESS_THAN",
+            PropertyOp::LessThanOrEqual => "LESS_THAN_OR_EQUAL",
+            PropertyOp::GreaterThan => "GREATER_THAN",
+            PropertyOp::GreaterThanOrEqual => "GREATER_THAN_OR_EQUAL",
+            PropertyOp::In => "IN",
+            PropertyOp::NotIn => "NOT_IN",
+        }
+    }
+}
+
+/// A query filter, built with the constructors below.
+#[derive(Debug, Clone)]
+pub struct Filter(FilterKind);
+
+#[derive(Debug, Clone)]
+enum FilterKind {
+    Property(Box<PropertyFilter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+#[derive(Debug, Clone)]
+struct PropertyFilter {
+    property: String,
+    op: &'static str,
+    /// The error if the value could not be serialized.
+    value: Result<google_datastore1::Value, Error>,
+}
+
+impl Filter {
+    pub fn property<P: ToString, V: Serialize>(property: P, op: PropertyOp, value: V) -> Self {
+        Filter(FilterKind::Property(Box::new(PropertyFilter {
+            property: property.to_string(),
+            op: op.as_str(),
+            value: convert::to_datastore_value(value),
+        })))
+    }
+    pub fn eq<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::Equal, value)
+    }
+    pub fn ne<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::NotEqual, value)
+    }
+    pub fn lt<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::LessThan, value)
+    }
+    pub fn le<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::LessThanOrEqual, value)
+    }
+    pub fn gt<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::GreaterThan, value)
+    }
+    pub fn ge<P: ToString, V: Serialize>(property: P, value: V) -> Self {
+        Filter::property(property, PropertyOp::GreaterThanOrEqual, value)
+    }
+    pub fn is_in<P: ToString, V: Serialize>(property: P, values: Vec<V>) -> Self {
+        Filter::property(property, PropertyOp::In, values)
+    }
+    pub fn not_in<P: ToString, V: Serialize>(property: P, values: Vec<V>) -> Self {
+        Filter::property(property, PropertyOp::NotIn, values)
+    }
+    /// Matches entities in the entity group below `key`, including `key`
+    /// itself. Queries inside a transaction need one.
+    pub fn has_ancestor(key: KeyPath) -> Self {
+        let key = google_datastore1::Key {
+            path: Some(key.into_datastore_path()),
+            partition_id: None,
+        };
+        Filter(FilterKind::Property(Box::new(PropertyFilter {
+            property: String::from("__key__"),
+            op: "HAS_ANCESTOR",
+            value: Ok(google_datastore1::Value {
+                key_value: Some(key),
+                ..google_datastore1::Value::default()
+            }),
+        })))
+    }
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter(FilterKind::And(filters))
+    }
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter(FilterKind::Or(filters))
+    }
+    /// Key values in the filter are placed in `partition_id`, as Datastore
+    /// rejects keys from another namespace than the query's.
+    fn to_datastore_filter(
+        &self,
+        partition_id: Option<&google_datastore1::PartitionId>,
+    ) -> Result<google_datastore1::Filter, Error> {
+        fn composite(
+            op: &str,
+            filters: &[Filter],
+            partition_id: Option<&google_datastore1::PartitionId>,
+        ) -> Result<google_datastore1::Filter, Error> {
+            let filters = filters
+                .iter()
+                .map(|x| x.to_datastore_filter(partition_id))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(google_datastore1::Filter {
+                composite_filter: Some(google_datastore1::CompositeFilter {
+                    filters: Some(filters),
+                    op: Some(String::from(op)),
+                }),
+                property_filter: None,
+            })
+        }
+        match &self.0 {
+            FilterKind::Property(filter) => {
+                let mut value = filter.value.clone()?;
+                if let Some(partition_id) = partition_id {
+                    convert::set_key_partitions(&mut value, partition_id);
+                }
+                Ok(google_datastore1::Filter {
+                    composite_filter: None,
+                    property_filter: Some(google_datastore1::PropertyFilter {
+                        property: Some(google_datastore1::PropertyReference {
+                            name: Some(filter.property.clone()),
+                        }),
+                        value: Some(value),
+                        op: Some(String::from(filter.op)),
+                    }),
+                })
+            }
+            FilterKind::And(filters) => composite("AND", filters, partition_id),
+            FilterKind::Or(filters) => composite("OR", filters, partition_id),
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// QUERY
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+/// A query over all entities of kind `T`.
+///
+/// ```no_run
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # pub struct TodoItem { pub name: String, pub title: String }
+/// # impl ddb::EntityKey for TodoItem {
+/// #     fn entity_kind_key() -> String { String::from("TodoItem") }
+/// #     fn entity_name_key(&self) -> String { self.name.clone() }
+/// # }
+/// use ddb::{Query, Filter, Direction};
+/// let db = ddb::DatastoreClient::new().unwrap();
+/// let query = Query::<TodoItem>::new()
+///     .filter(Filter::eq("title", "lorem ipsum"))
+///     .order_by("name", Direction::Ascending)
+///     .limit(10);
+/// let items: Vec<TodoItem> = db.query(&query).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Query<T> {
+    filter: Option<Filter>,
+    order: Vec<(String, Direction)>,
+    limit: Option<i32>,
+    offset: Option<i32>,
+    start_cursor: Option<Cursor>,
+    projection: Vec<String>,
+    distinct_on: Vec<String>,
+    keys_only: bool,
+    marker: PhantomData<T>,
+}
+
+impl<T> Clone for Query<T> {
+    fn clone(&self) -> Self {
+        Query {
+            filter: self.filter.clone(),
+            order: self.order.clone(),
+            limit: self.limit,
+            offset: self.offset,
+            start_cursor: self.start_cursor.clone(),
+            projection: self.projection.clone(),
+            distinct_on: self.distinct_on.clone(),
+            keys_only: self.keys_only,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: EntityKey> Default for Query<T> {
+    fn default() -> Self {
+        Query::new()
+    }
+}
+
+impl<T: EntityKey> Query<T> {
+    pub fn new() -> Self {
+        Query {
+            filter: None,
+            order: Vec::new(),
+            limit: None,
+            offset: None,
+            start_cursor: None,
+            projection: Vec::new(),
+            distinct_on: Vec::new(),
+            keys_only: false,
+            marker: PhantomData,
+        }
+    }
+    /// Adds a filter; multiple filters are combined with AND.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = match self.filter.take() {
+            None => Some(filter),
+            Some(Filter(FilterKind::And(mut filters))) => {
+                filters.push(filter);
+                Some(Filter::and(filters))
+            }
+            Some(existing) => Some(Filter::and(vec![existing, filter])),
+        };
+        self
+    }
+    /// Restricts the query to the entity group below `key`, as required
+    /// inside a transaction. Combined with other filters with AND.
+    pub fn ancestor(self, key: KeyPath) -> Self {
+        self.filter(Filter::has_ancestor(key))
+    }
+    /// Adds a sort order; earlier orders take precedence.
+    pub fn order_by<P: ToString>(mut self, property: P, direction: Direction) -> Self {
+        self.order.push((property.to_string(), direction));
+        self
+    }
+    pub fn limit(mut self, limit: i32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    pub fn offset(mut self, offset: i32) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+    /// Resumes the query where a previous page left off.
+    pub fn start_cursor(mut self, cursor: Cursor) -> Self {
+        self.start_cursor = Some(cursor);
+        self
+    }
+    /// Only fetch the given properties, e.g. to deserialize the results into
+    /// a lighter struct via `DatastoreClient::query_as`.
+    ///
+    /// Projected properties must be indexed.
+    pub fn project<P: ToString>(mut self, properties: &[P]) -> Self {
+        self.projection.extend(properties.iter().map(|x| x.to_string()));
+        self
+    }
+    /// Only return the first result for each distinct combination of values
+    /// of the given properties. These must also be projected.
+    pub fn distinct_on<P: ToString>(mut self, properties: &[P]) -> Self {
+        self.distinct_on.extend(properties.iter().map(|x| x.to_string()));
+        self
+    }
+    /// Only fetch the keys of matching entities, via the `__key__` projection.
+    pub(crate) fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self
+    }
+    /// Moves the query past `page`, keeping the overall limit and offset.
+    /// Returns whether `page` was the last one to fetch.
+    pub(crate) fn advance<U>(&mut self, page: &Page<U>) -> bool {
+        self.start_cursor = page.cursor.clone();
+        self.limit = page.remaining_limit(self.limit);
+        self.offset = page.remaining_offset(self.offset);
+        page.is_last(self.limit)
+    }
+    pub(crate) fn to_datastore_query(
+        &self,
+        partition_id: Option<&google_datastore1::PartitionId>,
+    ) -> Result<google_datastore1::Query, Error> {
+        let filter = match &self.filter {
+            Some(filter) => Some(filter.to_datastore_filter(partition_id)?),
+            None => None,
+        };
+        let order = self.order
+            .iter()
+            .map(|(property, direction)| {
+                google_datastore1::PropertyOrder {
+                    property: Some(google_datastore1::PropertyReference {
+                        name: Some(property.clone()),
+                    }),
+                    direction: Some(String::from(match direction {
+                        Direction::Ascending => "ASCENDING",
+                        Direction::Descending => "DESCENDING",
+                    })),
+                }
+            })
+            .collect::<Vec<_>>();
+        let to_property = |name: &str| {
+            google_datastore1::PropertyReference {
+                name: Some(name.to_owned()),
+            }
+        };
+        let projection = if self.keys_only {
+            vec![String::from("__key__")]
+        } else {
+            self.projection.clone()
+        };
+        let projection = projection
+            .iter()
+            .map(|x| google_datastore1::Projection { property: Some(to_property(x)) })
+            .collect::<Vec<_>>();
+        let distinct_on = self.distinct_on
+            .iter()
+            .map(|x| to_property(x))
+            .collect::<Vec<_>>();
+        Ok(google_datastore1::Query {
+            start_cursor: self.start_cursor.as_ref().map(|x| x.0.clone()),
+            kind: Some(vec![ google_datastore1::KindExpression { name: Some(T::entity_kind_key())} ]),
+            projection: if projection.is_empty() {None} else {Some(projection)},
+            distinct_on: if distinct_on.is_empty() {None} else {Some(distinct_on)},
+            filter,
+            limit: self.limit,
+            offset: self.offset,
+            end_cursor: None,
+            order: if order.is_empty() {None} else {Some(order)},
+        })
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// PAGINATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// Opaque position within the results of a query.
+///
+/// Safe to hand out to clients (e.g. as a `?page=` parameter) and pass back
+/// later via `Query::start_cursor`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cursor(String);
+
+impl Cursor {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Cursor {
+    fn from(x: String) -> Self {
+        Cursor(x)
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a batch of query results ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoreResults {
+    /// The batch was cut short; more results may follow.
+    NotFinished,
+    /// The query's limit was reached.
+    MoreResultsAfterLimit,
+    /// The query's end cursor was reached.
+    MoreResultsAfterCursor,
+    NoMoreResults,
+}
+
+impl MoreResults {
+    pub(crate) fn from_datastore(x: Option<&str>) -> Self {
+        match x {
+            Some("NOT_FINISHED") => MoreResults::NotFinished,
+            Some("MORE_RESULTS_AFTER_LIMIT") => MoreResults::MoreResultsAfterLimit,
+            Some("MORE_RESULTS_AFTER_CURSOR") => MoreResults::MoreResultsAfterCursor,
+            _ => MoreResults::NoMoreResults,
+        }
+    }
+}
+
+/// One batch of query results.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub results: Vec<T>,
+    /// Where the next page starts; pass to `Query::start_cursor`.
+    pub cursor: Option<Cursor>,
+    pub more_results: MoreResults,
+    /// How many results the query's offset skipped in this batch. When
+    /// resuming from `cursor`, reduce the offset by this much, as it counts
+    /// from the cursor.
+    pub skipped_results: i32,
+}
+
+impl<T> Page<T> {
+    pub(crate) fn new(results: Vec<T>, batch: &google_datastore1::QueryResultBatch) -> Self {
+        Page {
+            results,
+            cursor: batch.end_cursor.clone().map(Cursor),
+            more_results: MoreResults::from_datastore(batch.more_results.as_deref()),
+            skipped_results: batch.skipped_results.unwrap_or(0),
+        }
+    }
+    pub(crate) fn with_results<U>(self, results: Vec<U>) -> Page<U> {
+        Page {
+            results,
+            cursor: self.cursor,
+            more_results: self.more_results,
+            skipped_results: self.skipped_results,
+        }
+    }
+    /// Moves `query` past this page like `Query::advance` does, for queries
+    /// Datastore parsed from GQL. Returns whether this was the last page.
+    pub(crate) fn advance_datastore_query(&self, query: &mut google_datastore1::Query) -> bool {
+        query.start_cursor = self.cursor.as_ref().map(|x| x.as_str().to_owned());
+        query.limit = self.remaining_limit(query.limit);
+        query.offset = self.remaining_offset(query.offset);
+        self.is_last(query.limit)
+    }
+    fn remaining_limit(&self, limit: Option<i32>) -> Option<i32> {
+        limit.map(|x| x - self.results.len() as i32)
+    }
+    fn remaining_offset(&self, offset: Option<i32>) -> Option<i32> {
+        offset.map(|x| x - self.skipped_results)
+    }
+    /// Whether this is the last page to fetch for the query it came from,
+    /// given the limit left after it.
+    ///
+    /// Datastore may stop a batch at its own limit rather than the query's,
+    /// so `MoreResultsAfterLimit` only ends the query once `limit` is used up.
+    pub(crate) fn is_last(&self, limit: Option<i32>) -> bool {
+        if self.cursor.is_none() || matches!(limit, Some(x) if x <= 0) {
+            return true;
+        }
+        match self.more_results {
+            MoreResults::NotFinished | MoreResults::MoreResultsAfterLimit => false,
+            MoreResults::MoreResultsAfterCursor | MoreResults::NoMoreResults => true,
+        }
+    }
+    /// Whether resuming from `cursor` may yield further results.
+    pub fn has_more(&self) -> bool {
+        self.more_results != MoreResults::NoMoreResults
+    }
+}
+
+/// Iterator over the pages of a query, fetching each batch on demand
+/// until the query is exhausted or its limit is reached.
+///
+/// See `DatastoreClient::query_pages`.
+pub struct Pages<T> {
+    client: DatastoreClient,
+    query: Query<T>,
+    done: bool,
+}
+
+impl<T> Pages<T> {
+    pub(crate) fn new(client: DatastoreClient, query: Query<T>) -> Self {
+        Pages {
+            client,
+            query,
+            done: false,
+        }
+    }
+}
+
+impl<T: serde::de::DeserializeOwned + EntityKey> Iterator for Pages<T> {
+    type Item = Result<Page<T>, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = match self.client.query_page(&self.query) {
+            Ok(page) => page,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        self.done = self.query.advance(&page);
+        Some(Ok(page))
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// STREAMING
+///////////////////////////////////////////////////////////////////////////////
+
+/// Iterator over the results of a query, holding at most one batch in
+/// memory at a time.
+///
+/// See `DatastoreClient::query_iter`.
+pub struct QueryIter<T> {
+    client: DatastoreClient,
+    query: Query<T>,
+    buffer: std::vec::IntoIter<google_datastore1::Entity>,
+    done: bool,
+}
+
+impl<T> QueryIter<T> {
+    pub(crate) fn new(client: DatastoreClient, query: Query<T>) -> Self {
+        QueryIter {
+            client,
+            query,
+            buffer: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+impl<T: serde::de::DeserializeOwned + EntityKey> Iterator for QueryIter<T> {
+    type Item = Result<T, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entity) = self.buffer.next() {
+                return Some(convert::from_datastore_entity(entity));
+            }
+            if self.done {
+                return None;
+            }
+            match self.client.query_entity_page(&self.query) {
+                Ok(page) => {
+                    self.done = self.query.advance(&page);
+                    self.buffer = page.results.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::PathElement;
+
+    struct Task;
+
+    impl EntityKey for Task {
+        fn entity_kind_key() -> String {
+            String::from("Task")
+        }
+        fn entity_name_key(&self) -> String {
+            String::new()
+        }
+    }
+
+    fn property_filter(filter: &google_datastore1::Filter) -> &google_datastore1::PropertyFilter {
+        filter.property_filter.as_ref().unwrap()
+    }
+
+    fn composite_filter(filter: &google_datastore1::Filter) -> (&str, &[google_datastore1::Filter]) {
+        let filter = filter.composite_filter.as_ref().unwrap();
+        (filter.op.as_deref().unwrap(), filter.filters.as_deref().unwrap())
+    }
+
+    fn page(results: usize, cursor: Option<&str>, more_results: &str, skipped_results: i32) -> Page<()> {
+        let batch = google_datastore1::QueryResultBatch {
+            end_cursor: cursor.map(|x| x.to_owned()),
+            more_results: Some(more_results.to_owned()),
+            skipped_results: Some(skipped_results),
+            ..Default::default()
+        };
+        Page::new(vec![(); results], &batch)
+    }
+
+    #[test]
+    fn operators() {
+        let cases = vec![
+            (Filter::eq("done", true), "EQUAL"),
+            (Filter::ne("done", true), "NOT_EQUAL"),
+            (Filter::lt("done", true), "LESS_THAN"),
+            (Filter::le("done", true), "LESS_THAN_OR_EQUAL"),
+            (Filter::gt("done", true), "GREATER_THAN"),
+            (Filter::ge("done", true), "GREATER_THAN_OR_EQUAL"),
+            (Filter::is_in("done", vec![true]), "IN"),
+            (Filter::not_in("done", vec![true]), "NOT_IN"),
+        ];
+        for (filter, op) in cases {
+            let filter = filter.to_datastore_filter(None).unwrap();
+            let filter = property_filter(&filter);
+            assert_eq!(filter.op.as_deref(), Some(op));
+            assert_eq!(filter.property.as_ref().and_then(|x| x.name.as_deref()), Some("done"));
+        }
+        let filter = Filter::eq("done", true).to_datastore_filter(None).unwrap();
+        assert_eq!(property_filter(&filter).value.as_ref().and_then(|x| x.boolean_value), Some(true));
+        let filter = Filter::is_in("rank", vec![1, 2]).to_datastore_filter(None).unwrap();
+        let values = property_filter(&filter).value.clone().and_then(|x| x.array_value).and_then(|x| x.values);
+        assert_eq!(values.map(|x| x.len()), Some(2));
+    }
+
+    #[test]
+    fn nested_and_or() {
+        let filter = Filter::or(vec![
+            Filter::and(vec![Filter::eq("done", true), Filter::gt("rank", 1)]),
+            Filter::eq("pinned", true),
+        ]);
+        let filter = filter.to_datastore_filter(None).unwrap();
+        let (op, filters) = composite_filter(&filter);
+        assert_eq!(op, "OR");
+        assert_eq!(filters.len(), 2);
+        let (op, inner) = composite_filter(&filters[0]);
+        assert_eq!(op, "AND");
+        assert_eq!(property_filter(&inner[0]).op.as_deref(), Some("EQUAL"));
+        assert_eq!(property_filter(&inner[1]).op.as_deref(), Some("GREATER_THAN"));
+        assert_eq!(property_filter(&filters[1]).op.as_deref(), Some("EQUAL"));
+    }
+
+    #[test]
+    fn filters_are_combined_with_and() {
+        let query = Query::<Task>::new()
+            .filter(Filter::eq("done", true))
+            .filter(Filter::gt("rank", 1))
+            .filter(Filter::lt("rank", 9));
+        let query = query.to_datastore_query(None).unwrap();
+        let (op, filters) = composite_filter(query.filter.as_ref().unwrap());
+        assert_eq!(op, "AND");
+        assert_eq!(filters.len(), 3);
+    }
+
+    #[test]
+    fn unserializable_values_fail() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1], 1);
+        let query = Query::<Task>::new().filter(Filter::eq("done", map));
+        assert!(matches!(query.to_datastore_query(None), Err(Error::Serialization {..})));
+    }
+
+    #[test]
+    fn ancestor() {
+        let key = KeyPath::new(vec![PathElement::new("Org", "acme")], "Project", 7i64);
+        let partition_id = google_datastore1::PartitionId {
+            project_id: Some(String::from("project")),
+            namespace_id: Some(String::from("namespace")),
+        };
+        let query = Query::<Task>::new().ancestor(key);
+        let query = query.to_datastore_query(Some(&partition_id)).unwrap();
+        let filter = property_filter(query.filter.as_ref().unwrap());
+        assert_eq!(filter.op.as_deref(), Some("HAS_ANCESTOR"));
+        assert_eq!(filter.property.as_ref().and_then(|x| x.name.as_deref()), Some("__key__"));
+        let key = filter.value.clone().and_then(|x| x.key_value).unwrap();
+        assert_eq!(key.path.map(|x| x.len()), Some(2));
+        let namespace = key.partition_id.and_then(|x| x.namespace_id);
+        assert_eq!(namespace.as_deref(), Some("namespace"));
+    }
+
+    #[test]
+    fn order_limit_offset() {
+        let query = Query::<Task>::new()
+            .order_by("rank", Direction::Descending)
+            .order_by("name", Direction::Ascending)
+            .limit(10)
+            .offset(5);
+        let query = query.to_datastore_query(None).unwrap();
+        let order = query.order.unwrap()
+            .into_iter()
+            .map(|x| (x.property.and_then(|x| x.name).unwrap(), x.direction.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![
+            (String::from("rank"), String::from("DESCENDING")),
+            (String::from("name"), String::from("ASCENDING")),
+        ]);
+        assert_eq!(query.limit, Some(10));
+        assert_eq!(query.offset, Some(5));
+        assert_eq!(query.kind.unwrap()[0].name.as_deref(), Some("Task"));
+        let query = Query::<Task>::new().to_datastore_query(None).unwrap();
+        assert!(query.order.is_none());
+        assert!(query.filter.is_none());
+    }
+
+    #[test]
+    fn projection_and_distinct() {
+        let query = Query::<Task>::new()
+            .project(&["name", "rank"])
+            .distinct_on(&["name"]);
+        let query = query.to_datastore_query(None).unwrap();
+        let projection = query.projection.unwrap()
+            .into_iter()
+            .map(|x| x.property.and_then(|x| x.name).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(projection, vec!["name", "rank"]);
+        let distinct_on = query.distinct_on.unwrap()
+            .into_iter()
+            .map(|x| x.name.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(distinct_on, vec!["name"]);
+        let query = Query::<Task>::new().to_datastore_query(None).unwrap();
+        assert!(query.projection.is_none());
+        assert!(query.distinct_on.is_none());
+    }
+
+    #[test]
+    fn keys_only() {
+        let query = Query::<Task>::new().project(&["name"]).keys_only();
+        let query = query.to_datastore_query(None).unwrap();
+        let projection = query.projection.unwrap();
+        assert_eq!(projection.len(), 1);
+        assert_eq!(projection[0].property.as_ref().and_then(|x| x.name.as_deref()), Some("__key__"));
+        let query = Query::<Task>::new().to_datastore_query(None).unwrap();
+        assert!(query.projection.is_none());
+    }
+
+    #[test]
+    fn advance() {
+        let mut query = Query::<Task>::new().limit(10).offset(5);
+        let is_last = query.advance(&page(0, Some("a"), "NOT_FINISHED", 3));
+        assert!(!is_last);
+        assert_eq!(query.offset, Some(2));
+        assert_eq!(query.limit, Some(10));
+        assert_eq!(query.start_cursor, Some(Cursor::from(String::from("a"))));
+        // Datastore may stop a batch short of the query's limit.
+        let is_last = query.advance(&page(4, Some("b"), "MORE_RESULTS_AFTER_LIMIT", 2));
+        assert!(!is_last);
+        assert_eq!(query.offset, Some(0));
+        assert_eq!(query.limit, Some(6));
+        let is_last = query.advance(&page(6, Some("c"), "MORE_RESULTS_AFTER_LIMIT", 0));
+        assert!(is_last);
+        assert_eq!(query.limit, Some(0));
+        let query = query.to_datastore_query(None).unwrap();
+        assert_eq!(query.start_cursor.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn advance_datastore_query() {
+        // As Datastore parses `SELECT * FROM Task LIMIT 10 OFFSET 5`.
+        let mut query = google_datastore1::Query {
+            limit: Some(10),
+            offset: Some(5),
+            ..Default::default()
+        };
+        assert!(!page(0, Some("a"), "NOT_FINISHED", 5).advance_datastore_query(&mut query));
+        assert_eq!((query.limit, query.offset), (Some(10), Some(0)));
+        assert_eq!(query.start_cursor.as_deref(), Some("a"));
+        assert!(!page(4, Some("b"), "MORE_RESULTS_AFTER_LIMIT", 0).advance_datastore_query(&mut query));
+        assert_eq!(query.limit, Some(6));
+        assert!(page(6, Some("c"), "MORE_RESULTS_AFTER_LIMIT", 0).advance_datastore_query(&mut query));
+        assert_eq!(query.start_cursor.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn advance_without_limit() {
+        let mut query = Query::<Task>::new();
+        assert!(!query.advance(&page(300, Some("a"), "NOT_FINISHED", 0)));
+        assert_eq!(query.limit, None);
+        assert_eq!(query.offset, None);
+        assert!(query.advance(&page(10, Some("b"), "NO_MORE_RESULTS", 0)));
+        assert!(query.advance(&page(0, None, "NOT_FINISHED", 0)));
+    }
+}
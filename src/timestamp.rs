@@ -0,0 +1,240 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use crate::convert;
+
+///////////////////////////////////////////////////////////////////////////////
+// TIMESTAMP
+///////////////////////////////////////////////////////////////////////////////
+
+/// A date-time stored as a Datastore `timestamp_value`, instead of the
+/// string its own `Serialize` impl produces, so that it can be range-queried.
+///
+/// Use it as a field type or a filter value, e.g.
+/// `Filter::ge("created", Timestamp(Utc::now()))`. To keep a field's type,
+/// annotate it with `#[serde(with = "ddb::timestamp::chrono")]` or
+/// `#[serde(with = "ddb::timestamp::time")]` instead.
+///
+/// Requires the `chrono` or `time` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp<T>(pub T);
+
+/// Other serializers only see `value`.
+fn serialize_rfc3339<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct(convert::TIMESTAMP, value)
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// CHRONO
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "chrono")]
+impl Serialize for Timestamp<::chrono::DateTime<::chrono::Utc>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self::chrono::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<'de> Deserialize<'de> for Timestamp<::chrono::DateTime<::chrono::Utc>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        self::chrono::deserialize(deserializer).map(Timestamp)
+    }
+}
+
+/// For `DateTime<Utc>` fields: `#[serde(with = "ddb::timestamp::chrono")]`.
+#[cfg(feature = "chrono")]
+pub mod chrono {
+    use ::chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = value.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        super::serialize_rfc3339(&value, serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        parse(&String::deserialize(deserializer)?)
+    }
+    fn parse<E: de::Error>(value: &str) -> Result<DateTime<Utc>, E> {
+        DateTime::parse_from_rfc3339(value)
+            .map(|x| x.with_timezone(&Utc))
+            .map_err(de::Error::custom)
+    }
+
+    /// For `Option<DateTime<Utc>>` fields:
+    /// `#[serde(with = "ddb::timestamp::chrono::option")]`.
+    pub mod option {
+        use ::chrono::{DateTime, Utc};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(value: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(value) => super::parse(&value).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TIME
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "time")]
+impl Serialize for Timestamp<::time::OffsetDateTime> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self::time::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "time")]
+impl<'de> Deserialize<'de> for Timestamp<::time::OffsetDateTime> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        self::time::deserialize(deserializer).map(Timestamp)
+    }
+}
+
+/// For `OffsetDateTime` fields: `#[serde(with = "ddb::timestamp::time")]`.
+///
+/// Values are converted to UTC when stored.
+#[cfg(feature = "time")]
+pub mod time {
+    use ::time::{OffsetDateTime, UtcOffset};
+    use ::time::format_description::well_known::Rfc3339;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = value
+            .to_offset(UtcOffset::UTC)
+            .format(&Rfc3339)
+            .map_err(ser::Error::custom)?;
+        super::serialize_rfc3339(&value, serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OffsetDateTime, D::Error> {
+        parse(&String::deserialize(deserializer)?)
+    }
+    fn parse<E: de::Error>(value: &str) -> Result<OffsetDateTime, E> {
+        OffsetDateTime::parse(value, &Rfc3339).map_err(de::Error::custom)
+    }
+
+    /// For `Option<OffsetDateTime>` fields:
+    /// `#[serde(with = "ddb::timestamp::time::option")]`.
+    pub mod option {
+        use ::time::OffsetDateTime;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(value: &Option<OffsetDateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<OffsetDateTime>, D::Error> {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(value) => super::parse(&value).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use serde::{Serialize, Deserialize};
+    use crate::convert;
+    use super::Timestamp;
+
+    #[cfg(feature = "chrono")]
+    mod chrono {
+        use ::chrono::{DateTime, TimeZone, Utc};
+        use super::*;
+
+        /// 2020-01-02T03:04:05.123456Z.
+        fn date_time() -> DateTime<Utc> {
+            Utc.timestamp_opt(1_577_934_245, 123_456_000).unwrap()
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Event {
+            #[serde(with = "crate::timestamp::chrono")]
+            at: DateTime<Utc>,
+            #[serde(with = "crate::timestamp::chrono::option")]
+            until: Option<DateTime<Utc>>,
+        }
+
+        #[test]
+        fn timestamp_round_trips() {
+            let value = convert::to_datastore_value(Timestamp(date_time())).unwrap();
+            assert_eq!(value.timestamp_value.as_deref(), Some("2020-01-02T03:04:05.123456Z"));
+            assert_eq!(value.string_value, None);
+            let timestamp: Timestamp<DateTime<Utc>> = convert::from_datastore_value(value).unwrap();
+            assert_eq!(timestamp, Timestamp(date_time()));
+        }
+
+        #[test]
+        fn timestamp_field_round_trips() {
+            for until in [None, Some(date_time())] {
+                let event = Event {at: date_time(), until};
+                let value = convert::to_datastore_value(&event).unwrap();
+                let properties = value.entity_value.clone().and_then(|x| x.properties).unwrap();
+                assert_eq!(properties["at"].timestamp_value.as_deref(), Some("2020-01-02T03:04:05.123456Z"));
+                assert_eq!(properties["until"].timestamp_value.is_some(), until.is_some());
+                assert_eq!(convert::from_datastore_value::<Event>(value).unwrap(), event);
+            }
+        }
+    }
+
+    #[cfg(feature = "time")]
+    mod time {
+        use ::time::{OffsetDateTime, UtcOffset};
+        use super::*;
+
+        /// 2020-01-02T05:04:05+02:00, i.e. 2020-01-02T03:04:05Z.
+        fn date_time() -> OffsetDateTime {
+            OffsetDateTime::from_unix_timestamp(1_577_934_245)
+                .unwrap()
+                .to_offset(UtcOffset::from_hms(2, 0, 0).unwrap())
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Event {
+            #[serde(with = "crate::timestamp::time")]
+            at: OffsetDateTime,
+            #[serde(with = "crate::timestamp::time::option")]
+            until: Option<OffsetDateTime>,
+        }
+
+        #[test]
+        fn timestamp_is_stored_in_utc() {
+            let value = convert::to_datastore_value(Timestamp(date_time())).unwrap();
+            assert_eq!(value.timestamp_value.as_deref(), Some("2020-01-02T03:04:05Z"));
+            assert_eq!(value.string_value, None);
+            let timestamp: Timestamp<OffsetDateTime> = convert::from_datastore_value(value).unwrap();
+            assert_eq!(timestamp.0, date_time());
+            assert_eq!(timestamp.0.offset(), UtcOffset::UTC);
+        }
+
+        #[test]
+        fn timestamp_field_round_trips() {
+            for until in [None, Some(date_time())] {
+                let event = Event {at: date_time(), until};
+                let value = convert::to_datastore_value(&event).unwrap();
+                let properties = value.entity_value.clone().and_then(|x| x.properties).unwrap();
+                assert_eq!(properties["at"].timestamp_value.as_deref(), Some("2020-01-02T03:04:05Z"));
+                assert_eq!(properties["until"].timestamp_value.is_some(), until.is_some());
+                assert_eq!(convert::from_datastore_value::<Event>(value).unwrap(), event);
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::time::Duration;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::batch;
+use crate::db::{CommitResult, DatastoreClient, EntityKey, Error, GqlQuery, Key, KeyId, KeyPath, PathElement, Query};
+
+///////////////////////////////////////////////////////////////////////////////
+// TRANSACTION
+///////////////////////////////////////////////////////////////////////////////
+
+/// A read-write transaction.
+///
+/// Reads see a consistent snapshot and are validated at commit time; writes
+/// are buffered locally and only sent on `commit`. Queries inside a
+/// transaction must be ancestor queries, see `Query::ancestor`.
+///
+/// A transaction that is dropped without calling `commit` or `rollback`
+/// expires on the server.
+pub struct Transaction {
+    /// Tagged with the transaction id, so reads go through the transaction.
+    client: DatastoreClient,
+    mutations: RefCell<Vec<google_datastore1::Mutation>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(client: DatastoreClient) -> Self {
+        Transaction {
+            client,
+            mutations: RefCell::new(Vec::new()),
+        }
+    }
+    pub fn id(&self) -> &str {
+        self.client
+            .transaction_id()
+            .expect("transaction client without a transaction id")
+    }
+    pub fn get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<T, Error> {
+        self.client.get(key)
+    }
+    pub fn get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<T, Error> {
+        self.client.get_with_ancestors(ancestors, key)
+    }
+    pub fn try_get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<Option<T>, Error> {
+        self.client.try_get(key)
+    }
+    pub fn try_get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<Option<T>, Error> {
+        self.client.try_get_with_ancestors(ancestors, key)
+    }
+    pub fn get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<T, Error> {
+        self.client.get_by_key(key)
+    }
+    pub fn try_get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<Option<T>, Error> {
+        self.client.try_get_by_key(key)
+    }
+    pub fn get_many<T, K, I>(&self, keys: I) -> Result<Vec<Option<T>>, Error>
+    where
+        T: DeserializeOwned + EntityKey,
+        K: Into<KeyId>,
+        I: IntoIterator<Item = K>,
+    {
+        self.client.get_many(keys)
+    }
+    pub fn get_many_by_path<T: DeserializeOwned>(&self, keys: &[KeyPath]) -> Result<Vec<Option<T>>, Error> {
+        self.client.get_many_by_path(keys)
+    }
+    pub fn query<T: DeserializeOwned + EntityKey>(&self, query: &Query<T>) -> Result<Vec<T>, Error> {
+        self.client.query(query)
+    }
+    pub fn query_keys<T: EntityKey>(&self, query: &Query<T>) -> Result<Vec<KeyPath>, Error> {
+        self.client.query_keys(query)
+    }
+    pub fn insert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.push(batch::insert_mutation(entity));
+        Ok(())
+    }
+    pub fn upsert<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.push(batch::upsert_mutation(entity));
+        Ok(())
+    }
+    pub fn update<T: Serialize + EntityKey>(&self, value: T) -> Result<(), Error> {
+        let entity = self.client.to_entity(value)?;
+        self.push(batch::update_mutation(entity));
+        Ok(())
+    }
+    pub fn delete<T: EntityKey, K: Into<KeyId>>(&self, key: K) {
+        self.delete_with_ancestors::<T, K>(&[], key)
+    }
+    pub fn delete_with_ancestors<T: EntityKey, K: Into<KeyId>>(&self, ancestors: &[PathElement], key: K) {
+        let key = KeyPath::new(ancestors.to_vec(), T::entity_kind_key(), key);
+        let key = self.client.to_key(key.into_datastore_path());
+        self.push(batch::delete_mutation(key));
+    }
+    /// Sends the buffered writes. Fails if another transaction modified any
+    /// of the entities read or written by this one.
+    pub fn commit(self) -> Result<CommitResult, Error> {
+        self.client.commit_mutations(self.mutations.into_inner())
+    }
+    /// Discards the buffered writes and releases the transaction.
+    pub fn rollback(self) -> Result<(), Error> {
+        self.client.rollback_transaction(self.id())
+    }
+    fn push(&self, mutation: google_datastore1::Mutation) {
+        self.mutations.borrow_mut().push(mutation);
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// READ-ONLY TRANSACTION
+///////////////////////////////////////////////////////////////////////////////
+
+/// A read-only transaction: every read made through it sees the same
+/// consistent snapshot of the database.
+///
+/// Unlike read-write transactions, queries need not be ancestor queries.
+pub struct ReadOnlyTransaction {
+    /// Tagged with the transaction id, so reads go through the transaction.
+    client: DatastoreClient,
+}
+
+impl ReadOnlyTransaction {
+    pub(crate) fn new(client: DatastoreClient) -> Self {
+        ReadOnlyTransaction {
+            client,
+        }
+    }
+    pub fn id(&self) -> &str {
+        self.client
+            .transaction_id()
+            .expect("transaction client without a transaction id")
+    }
+    pub fn get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<T, Error> {
+        self.client.get(key)
+    }
+    pub fn get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<T, Error> {
+        self.client.get_with_ancestors(ancestors, key)
+    }
+    pub fn try_get<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(&self, key: K) -> Result<Option<T>, Error> {
+        self.client.try_get(key)
+    }
+    pub fn try_get_with_ancestors<T: DeserializeOwned + EntityKey, K: Into<KeyId>>(
+        &self,
+        ancestors: &[PathElement],
+        key: K,
+    ) -> Result<Option<T>, Error> {
+        self.client.try_get_with_ancestors(ancestors, key)
+    }
+    pub fn get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<T, Error> {
+        self.client.get_by_key(key)
+    }
+    pub fn try_get_by_key<T: DeserializeOwned + EntityKey>(&self, key: &Key<T>) -> Result<Option<T>, Error> {
+        self.client.try_get_by_key(key)
+    }
+    pub fn get_many<T, K, I>(&self, keys: I) -> Result<Vec<Option<T>>, Error>
+    where
+        T: DeserializeOwned + EntityKey,
+        K: Into<KeyId>,
+        I: IntoIterator<Item = K>,
+    {
+        self.client.get_many(keys)
+    }
+    pub fn get_many_by_path<T: DeserializeOwned>(&self, keys: &[KeyPath]) -> Result<Vec<Option<T>>, Error> {
+        self.client.get_many_by_path(keys)
+    }
+    pub fn query<T: DeserializeOwned + EntityKey>(&self, query: &Query<T>) -> Result<Vec<T>, Error> {
+        self.client.query(query)
+    }
+    pub fn query_as<T: EntityKey, P: DeserializeOwned>(&self, query: &Query<T>) -> Result<Vec<P>, Error> {
+        self.client.query_as(query)
+    }
+    pub fn query_keys<T: EntityKey>(&self, query: &Query<T>) -> Result<Vec<KeyPath>, Error> {
+        self.client.query_keys(query)
+    }
+    pub fn gql_query<T: DeserializeOwned>(&self, query: &GqlQuery) -> Result<Vec<T>, Error> {
+        self.client.gql_query(query)
+    }
+    pub fn count<T: EntityKey>(&self, query: &Query<T>) -> Result<i64, Error> {
+        self.client.count(query)
+    }
+    /// Releases the transaction.
+    pub fn close(self) -> Result<(), Error> {
+        self.client.rollback_transaction(self.id())
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// RETRIES
+///////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const MAX_ATTEMPTS: usize = 5;
+
+/// Whether the transaction lost a race and may succeed if retried.
+pub(crate) fn is_contention(e: &Error) -> bool {
+    matches!(e, Error::Aborted {..})
+}
+
+/// Sleeps for an exponentially growing, jittered delay.
+pub(crate) fn backoff(attempt: usize) {
+    use rand::Rng;
+    let base = 100 * 2u64.pow(attempt as u32);
+    let jitter = rand::thread_rng().gen_range(0, base);
+    std::thread::sleep(Duration::from_millis(base + jitter));
+}